@@ -18,7 +18,24 @@ pub fn init_frame_allocator(meta: &MachineMeta) {
     let start = ehypervisor as usize;
     let phys_mem_end = meta.phys_mem_start + meta.phys_mem_size;
     let size = phys_mem_end - start;
-    PHYS_FRAME_ALLOCATOR.lock().init(start.into(), size);
+    let mut allocator = PHYS_FRAME_ALLOCATOR.lock();
+    allocator.init(start.into(), size);
+    // e.g. firmware memory
+    for region in meta.reserved_memory.iter() {
+        let region_start = align_down(region.start, PAGE_SIZE_4K).max(allocator.base);
+        let region_end = align_up(region.start + region.size, PAGE_SIZE_4K)
+            .min(allocator.base + allocator.total_frames * PAGE_SIZE_4K);
+        if region_start < region_end {
+            info!(
+                "[Hypervisor] reserve memory [{:#x}, {:#x})",
+                region_start, region_end
+            );
+            allocator.alloc_range(
+                region_start.into(),
+                (region_end - region_start) / PAGE_SIZE_4K,
+            );
+        }
+    }
 }
 
 pub struct PhysFrameAllocator {
@@ -72,8 +89,8 @@ impl PhysFrameAllocator {
     pub fn dealloc_frames(&mut self, pos: HostPhysAddr, num_frames: usize) {
         // TODO: not decrease `used_frames` if deallocation failed
         self.used_frames -= num_frames;
-        self.inner
-            .dealloc((pos.as_usize() - self.base) / PAGE_SIZE_4K)
+        let start = (pos.as_usize() - self.base) / PAGE_SIZE_4K;
+        self.inner.insert(start..start + num_frames);
     }
 
     pub fn alloc_range(&mut self, start: HostPhysAddr, num_frames: usize) {
@@ -1,4 +1,15 @@
 pub const PAGE_SIZE_4K: usize = 0x1000;
+pub const PAGE_SIZE_2M: usize = 0x20_0000;
 pub const BOOT_STACK_SIZE: usize = 1000 * PAGE_SIZE_4K;
 
-pub const PCPU_STACK_SIZE: usize = 4 * PAGE_SIZE_4K;
+pub const PCPU_STACK_SIZE: usize = 16 * PAGE_SIZE_4K;
+pub const TRAP_STACK_SIZE: usize = 10 * PAGE_SIZE_4K;
+
+/// Guest RAM is backed by host frames allocated in chunks of this size.
+pub const GUEST_MEMORY_CHUNK_SIZE: usize = PAGE_SIZE_2M;
+
+/// Frequency of the `time` CSR on qemu virt machine, used if the device tree
+/// does not give one.
+pub const TIMEBASE_FREQUENCY: usize = 10_000_000;
+/// How long a vcpu may run before being preempted.
+pub const SCHED_TIME_SLICE_MS: usize = 10;
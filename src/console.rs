@@ -1,58 +1,72 @@
 use core::fmt::{self, Write};
 
+use spin::Mutex;
+
 use crate::mem::{page_table::HYPERVISOR_PAGE_TABLE, HYPERVISOR_PAGE_TABLE_INITED};
 
+// serialize output from different harts
+static STDOUT_LOCK: Mutex<()> = Mutex::new(());
+
 struct Stdout;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         if HYPERVISOR_PAGE_TABLE_INITED.load(core::sync::atomic::Ordering::SeqCst) {
-            // after page table inited, we can use console_write to print non-ascii characters
-            let str_vaddr = s.as_ptr() as usize;
-            let mut global_offset = 0;
-            loop {
-                let range_offset = global_offset;
-                let range_start_vaddr = str_vaddr + global_offset;
-                let range_start_paddr = HYPERVISOR_PAGE_TABLE
+            write_bytes(s.as_bytes());
+        } else {
+            // before page table inited, we can only print ascii characters
+            console_putstr(s);
+        }
+        Ok(())
+    }
+}
+
+fn write_bytes(s: &[u8]) {
+    if HYPERVISOR_PAGE_TABLE_INITED.load(core::sync::atomic::Ordering::SeqCst) {
+        // after page table inited, we can use console_write to print non-ascii characters
+        let str_vaddr = s.as_ptr() as usize;
+        let mut global_offset = 0;
+        loop {
+            let range_offset = global_offset;
+            let range_start_vaddr = str_vaddr + global_offset;
+            let range_start_paddr = HYPERVISOR_PAGE_TABLE
+                .lock()
+                .translate(range_start_vaddr.into())
+                .expect("failed to query physical addr for printing content");
+            while global_offset < s.len() {
+                let vaddr = str_vaddr + global_offset;
+                let paddr = HYPERVISOR_PAGE_TABLE
                     .lock()
-                    .translate(range_start_vaddr.into())
+                    .translate(vaddr.into())
                     .expect("failed to query physical addr for printing content");
-                while global_offset < s.len() {
-                    let vaddr = str_vaddr + global_offset;
-                    let paddr = HYPERVISOR_PAGE_TABLE
-                        .lock()
-                        .translate(vaddr.into())
-                        .expect("failed to query physical addr for printing content");
-                    if paddr.as_usize() - range_start_paddr.as_usize()
-                        != global_offset - range_offset
-                    {
-                        break;
-                    }
-                    global_offset += 1;
+                if paddr.as_usize() - range_start_paddr.as_usize() != global_offset - range_offset {
+                    break;
                 }
+                global_offset += 1;
+            }
 
-                // this is a continuous physical slice
-                let ret = sbi_rt::console_write(sbi_rt::Physical::new(
-                    global_offset - range_offset,
-                    range_start_paddr.as_usize(),
-                    0,
-                ));
-                if ret.is_err() {
-                    panic!(
-                        "[Hypervisor] failed to write to console, err: {:?}",
-                        ret.err()
-                    )
-                }
+            // this is a continuous physical slice
+            let ret = sbi_rt::console_write(sbi_rt::Physical::new(
+                global_offset - range_offset,
+                range_start_paddr.as_usize(),
+                0,
+            ));
+            if ret.is_err() {
+                panic!(
+                    "[Hypervisor] failed to write to console, err: {:?}",
+                    ret.err()
+                )
+            }
 
-                if global_offset == s.len() {
-                    break;
-                }
+            if global_offset == s.len() {
+                break;
             }
-        } else {
-            // before page table inited, we can only print ascii characters
-            console_putstr(s);
         }
-        Ok(())
+    } else {
+        for c in s {
+            #[allow(deprecated)]
+            sbi_rt::legacy::console_putchar(*c as usize);
+        }
     }
 }
 
@@ -64,7 +78,26 @@ pub fn console_putstr(s: &str) {
     }
 }
 
+/// Writes raw bytes, which need not be UTF-8, e.g. guest console output.
+pub fn print_bytes(bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    let _guard = STDOUT_LOCK.lock();
+    write_bytes(bytes);
+}
+
+/// Reads one byte of console input if there is any.
+#[allow(deprecated)]
+pub fn getchar() -> Option<u8> {
+    match sbi_rt::legacy::console_getchar() {
+        usize::MAX => None,
+        c => Some(c as u8),
+    }
+}
+
 pub fn print(args: fmt::Arguments) {
+    let _guard = STDOUT_LOCK.lock();
     Stdout.write_fmt(args).unwrap();
 }
 
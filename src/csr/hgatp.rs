@@ -15,6 +15,20 @@ impl Hgatp {
         private::write(*self);
     }
 
+    /// Number of VMID bits implemented by this hart, probed by writing all ones to VMID.
+    pub fn vmid_bits() -> usize {
+        let old = Self::read();
+        let mut hgatp = old;
+        hgatp.set_vmid(usize::MAX.get_bits(0..14));
+        hgatp.write();
+        let vmid = Self::read().vmid();
+        old.write();
+        unsafe {
+            core::arch::asm!("hfence.gvma");
+        }
+        (usize::BITS - vmid.leading_zeros()) as usize
+    }
+
     /// Guest address translation mode.
     #[inline]
     pub fn mode(&self) -> Mode {
@@ -52,6 +66,7 @@ pub enum Mode {
     Bare = 0,
     Sv39x4 = 8,
     Sv48x4 = 9,
+    Sv57x4 = 10,
 }
 impl From<usize> for Mode {
     fn from(x: usize) -> Self {
@@ -59,6 +74,7 @@ impl From<usize> for Mode {
             0 => Self::Bare,
             8 => Self::Sv39x4,
             9 => Self::Sv48x4,
+            10 => Self::Sv57x4,
             _ => unreachable!(),
         }
     }
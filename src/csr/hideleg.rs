@@ -20,6 +20,16 @@ impl Hideleg {
         self.bits
     }
 
+    /// Software Interrupt
+    #[inline]
+    pub fn vs_software_interrupt(&self) -> bool {
+        self.bits.get_bit(2)
+    }
+    #[inline]
+    pub fn set_vs_software_interrupt(&mut self, val: bool) {
+        self.bits.set_bit(2, val);
+    }
+
     /// Timer Interrupt
     #[inline]
     pub fn vs_timer_interrupt(&self) -> bool {
@@ -15,6 +15,11 @@ impl Hstatus {
         private::write(*self);
     }
 
+    #[inline]
+    pub fn from_bits(x: usize) -> Self {
+        Self { bits: x }
+    }
+
     #[inline]
     pub fn bits(&self) -> usize {
         self.bits
@@ -40,6 +45,16 @@ impl Hstatus {
         self.bits.set_bit(8, val);
     }
 
+    /// Virtual Trap WFI.
+    #[inline]
+    pub fn vtw(&self) -> bool {
+        self.bits.get_bit(21)
+    }
+    #[inline]
+    pub fn set_vtw(&mut self, val: bool) {
+        self.bits.set_bit(21, val);
+    }
+
     /// Guest Virtual Address.
     #[inline]
     pub fn gva(&self) -> bool {
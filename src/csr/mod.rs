@@ -1,14 +1,24 @@
 mod hcounteren;
 mod hedeleg;
+pub mod henvcfg;
 mod hgatp;
 mod hideleg;
 mod hstatus;
+pub mod htimedelta;
 pub mod htinst;
 pub mod htval;
 mod hvip;
 mod scause;
 mod sstatus;
+pub mod vsatp;
+pub mod vscause;
+pub mod vsepc;
+pub mod vsie;
+pub mod vsscratch;
 mod vsstatus;
+pub mod vstimecmp;
+pub mod vstval;
+pub mod vstvec;
 
 pub use hcounteren::*;
 pub use hedeleg::*;
@@ -20,8 +30,21 @@ pub use scause::*;
 pub use sstatus::*;
 pub use vsstatus::*;
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use log::debug;
 
+/// `henvcfg.STCE`, lets VS-mode use `stimecmp` (backed by `vstimecmp`).
+const HENVCFG_STCE: usize = 1 << 63;
+
+static SSTC_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether guest timers are programmed through `vstimecmp` rather than
+/// multiplexed onto the hypervisor timer.
+pub fn sstc_supported() -> bool {
+    SSTC_SUPPORTED.load(Ordering::Relaxed)
+}
+
 pub fn init_csrs() {
     let mut hstatus = Hstatus::read();
     hstatus.set_spv(true);
@@ -39,6 +62,7 @@ pub fn init_csrs() {
     debug!("[HyperVisor] hedeleg: {:?}", Hedeleg::read());
 
     let mut hideleg = Hideleg::read();
+    hideleg.set_vs_software_interrupt(true);
     hideleg.set_vs_timer_interrupt(true);
     hideleg.set_vs_external_interrupt(true);
     hideleg.write();
@@ -48,6 +72,12 @@ pub fn init_csrs() {
     hcounteren.write();
     debug!("[HyperVisor] hcounteren: {:?}", Hcounteren::read());
 
+    // STCE is read-only zero unless the platform implements Sstc
+    henvcfg::write(henvcfg::read() | HENVCFG_STCE);
+    let sstc = henvcfg::read() & HENVCFG_STCE != 0;
+    SSTC_SUPPORTED.store(sstc, Ordering::Relaxed);
+    debug!("[Hypervisor] sstc supported: {}", sstc);
+
     let mut hvip = Hvip::read();
     hvip.set_vs_external_interrupt(false);
     hvip.set_vs_software_interrupt(false);
@@ -57,7 +87,8 @@ pub fn init_csrs() {
 
     unsafe {
         riscv::register::sie::set_sext();
-        //     riscv::register::sie::set_ssoft();
+        // used by other pcpus to kick this one
+        riscv::register::sie::set_ssoft();
         riscv::register::sie::set_stimer();
         //     debug!("[Hypervisor] sie: {:?}", riscv::register::sie::read());
     }
@@ -20,6 +20,27 @@ impl Sstatus {
         self.bits
     }
 
+    #[inline]
+    pub fn from_bits(x: usize) -> Self {
+        Self { bits: x }
+    }
+
+    /// Vector extension state.
+    #[inline]
+    pub fn set_vs(&mut self, val: ExtensionState) {
+        self.bits.set_bits(9..11, val as usize);
+    }
+
+    /// Floating-point extension state.
+    #[inline]
+    pub fn fs(&self) -> ExtensionState {
+        ExtensionState::from(self.bits.get_bits(13..15))
+    }
+    #[inline]
+    pub fn set_fs(&mut self, val: ExtensionState) {
+        self.bits.set_bits(13..15, val as usize);
+    }
+
     #[inline]
     pub fn spp(&self) -> bool {
         self.bits.get_bit(8)
@@ -31,6 +52,26 @@ impl Sstatus {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum ExtensionState {
+    Off = 0,
+    Initial = 1,
+    Clean = 2,
+    Dirty = 3,
+}
+impl From<usize> for ExtensionState {
+    fn from(x: usize) -> Self {
+        match x {
+            0 => Self::Off,
+            1 => Self::Initial,
+            2 => Self::Clean,
+            3 => Self::Dirty,
+            _ => unreachable!(),
+        }
+    }
+}
+
 mod private {
     use super::Sstatus;
     use riscv::{read_csr_as, write_csr_as};
@@ -12,12 +12,22 @@ impl Vsstatus {
     pub fn write(&self) {
         private::write(*self);
     }
+
+    #[inline]
+    pub fn from_bits(x: usize) -> Self {
+        Self { bits: x }
+    }
+
+    #[inline]
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
 }
 
 mod private {
     use super::Vsstatus;
     use riscv::{read_csr_as, write_csr_as};
 
-    read_csr_as!(Vsstatus, 1536);
-    write_csr_as!(Vsstatus, 1536);
+    read_csr_as!(Vsstatus, 0x200);
+    write_csr_as!(Vsstatus, 0x200);
 }
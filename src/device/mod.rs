@@ -0,0 +1,15 @@
+mod uart16550;
+mod vplic;
+
+pub use uart16550::*;
+pub use vplic::*;
+
+/// A device emulated by trapping guest accesses to its registers.
+pub trait MmioDevice: Send + Sync {
+    /// Size of the register window in bytes.
+    fn size(&self) -> usize;
+    /// Reads `width` bytes at `offset` into the window.
+    fn read(&self, offset: usize, width: usize) -> usize;
+    /// Writes the low `width` bytes of `value` at `offset` into the window.
+    fn write(&self, offset: usize, width: usize, value: usize);
+}
@@ -0,0 +1,205 @@
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::vm::{self, VConsole};
+
+use super::{IrqLine, MmioDevice};
+
+/// Size of the register window, one byte per register.
+pub const UART16550_SIZE: usize = 0x100;
+
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// MSR with DCD, DSR and CTS asserted, as if a terminal were attached.
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// Emulated NS16550A UART backed by the console of its vm.
+///
+/// Transmission completes immediately, so the transmitter is always empty.
+/// Received bytes are taken from the console one at a time. Registers are one
+/// byte wide, wider accesses only see the low byte.
+///
+/// The interrupt line is updated on register accesses and when console input
+/// arrives, see `input_available`.
+pub struct Uart16550 {
+    regs: Mutex<Registers>,
+    console: Arc<VConsole>,
+    irq: Option<IrqLine>,
+}
+
+#[derive(Debug, Default)]
+struct Registers {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    fifo_enabled: bool,
+    /// Byte received from the console and not read by the guest yet.
+    rx: Option<u8>,
+    /// The "THR empty" interrupt is pending, cleared by reading IIR or writing THR.
+    thr_empty_pending: bool,
+}
+
+impl Uart16550 {
+    pub fn new(console: Arc<VConsole>, irq: Option<IrqLine>) -> Self {
+        Self {
+            regs: Mutex::new(Registers::default()),
+            console,
+            irq,
+        }
+    }
+
+    /// Takes console input into the receive register and updates the interrupt
+    /// line, to be called when input arrives.
+    pub fn input_available(&self) {
+        let mut regs = self.regs.lock();
+        regs.poll_rx(&self.console);
+        let pending = regs.irq_pending();
+        drop(regs);
+        self.update_irq(pending);
+    }
+
+    fn update_irq(&self, pending: bool) {
+        if let Some(irq) = self.irq.as_ref() {
+            irq.set_level(pending);
+        }
+    }
+}
+
+impl MmioDevice for Uart16550 {
+    fn size(&self) -> usize {
+        UART16550_SIZE
+    }
+
+    fn read(&self, offset: usize, _width: usize) -> usize {
+        // the input hook takes the registers lock
+        vm::poll_host_input();
+        let mut regs = self.regs.lock();
+        let value = regs.read(offset, &self.console);
+        let pending = regs.irq_pending();
+        drop(regs);
+        self.update_irq(pending);
+        value as usize
+    }
+
+    fn write(&self, offset: usize, _width: usize, value: usize) {
+        let mut regs = self.regs.lock();
+        regs.write(offset, value as u8, &self.console);
+        let pending = regs.irq_pending();
+        drop(regs);
+        self.update_irq(pending);
+    }
+}
+
+impl Registers {
+    fn read(&mut self, offset: usize, console: &VConsole) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll,
+            RBR_THR_DLL => {
+                self.poll_rx(console);
+                self.rx.take().unwrap_or(0)
+            }
+            IER_DLM if dlab => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                self.poll_rx(console);
+                let fifo = if self.fifo_enabled {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                let iir = if self.rx_interrupt() {
+                    IIR_RX_AVAILABLE
+                } else if self.thr_empty_interrupt() {
+                    // reading IIR acknowledges the interrupt
+                    self.thr_empty_pending = false;
+                    IIR_THR_EMPTY
+                } else {
+                    IIR_NO_INTERRUPT
+                };
+                iir | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll_rx(console);
+                let data_ready = if self.rx.is_some() { LSR_DATA_READY } else { 0 };
+                data_ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u8, console: &VConsole) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll = value,
+            RBR_THR_DLL => {
+                console.write(&[value]);
+                self.thr_empty_pending = true;
+            }
+            IER_DLM if dlab => self.dlm = value,
+            IER_DLM => {
+                // enabling the interrupt with an empty transmitter raises it
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            IIR_FCR => self.fifo_enabled = value & FCR_FIFO_ENABLE != 0,
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.rx_interrupt() || self.thr_empty_interrupt()
+    }
+
+    fn rx_interrupt(&self) -> bool {
+        self.ier & IER_RX_AVAILABLE != 0 && self.rx.is_some()
+    }
+
+    fn thr_empty_interrupt(&self) -> bool {
+        self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending
+    }
+
+    fn poll_rx(&mut self, console: &VConsole) {
+        if self.rx.is_none() {
+            let mut c = [0];
+            if console.pop_input(&mut c) == 1 {
+                self.rx = Some(c[0]);
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::config::PAGE_SIZE_4K;
+use crate::error::{HypervisorError, HypervisorResult};
+use crate::mem::align_up;
+use crate::vm::GLOBAL_VMS;
+use crate::{plic, sched};
+
+use super::MmioDevice;
+
+/// Interrupt sources of the virtual PLIC, source 0 does not exist.
+pub const VPLIC_NUM_SOURCES: usize = 1024;
+const WORDS: usize = VPLIC_NUM_SOURCES / 32;
+/// Guest external interrupt lines are tracked in a u64.
+const MAX_VCPUS: usize = 64;
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// Priorities and thresholds are 3 bits wide, as on qemu virt.
+const PRIORITY_MASK: u32 = 0x7;
+
+/// Emulated PLIC of a vm.
+///
+/// Contexts follow the qemu virt layout, context `2 * n` is the M-mode context of
+/// vcpu `n` and is never delivered, context `2 * n + 1` its S-mode context which
+/// drives the guest external interrupt. Emulated devices are level triggered,
+/// host sources routed to the vm are raised when the hypervisor claims them on
+/// the host and completed on the host when the guest completes them.
+pub struct VPlic {
+    vm_id: usize,
+    num_vcpu: usize,
+    /// Host sources routed to this vm.
+    passthrough: [u32; WORDS],
+    state: Mutex<VPlicState>,
+}
+
+struct VPlicState {
+    priority: Vec<u32>,
+    pending: [u32; WORDS],
+    /// Claimed and not completed yet.
+    in_service: [u32; WORDS],
+    /// Interrupt lines of emulated devices.
+    level: [u32; WORDS],
+    contexts: Vec<Context>,
+}
+
+#[derive(Clone)]
+struct Context {
+    enable: [u32; WORDS],
+    threshold: u32,
+}
+
+impl VPlic {
+    pub fn new(
+        vm_id: usize,
+        num_vcpu: usize,
+        passthrough_irqs: &[usize],
+    ) -> HypervisorResult<Self> {
+        if num_vcpu > MAX_VCPUS || !passthrough_irqs.iter().all(|s| is_valid_source(*s)) {
+            return Err(HypervisorError::InvalidParam);
+        }
+        let mut passthrough = [0; WORDS];
+        for source in passthrough_irqs {
+            set_bit(&mut passthrough, *source, true);
+        }
+        Ok(Self {
+            vm_id,
+            num_vcpu,
+            passthrough,
+            state: Mutex::new(VPlicState::new(2 * num_vcpu)),
+        })
+    }
+
+    /// Raises a host source routed to this vm, the hypervisor has claimed it.
+    pub fn raise(&self, source: usize) {
+        self.update(|state| set_bit(&mut state.pending, source, true));
+    }
+
+    /// Sets the interrupt line of an emulated device.
+    pub fn set_level(&self, source: usize, level: bool) {
+        self.update(|state| {
+            set_bit(&mut state.level, source, level);
+            // a source stays pending until claimed, or until its line drops
+            let pending = level && !get_bit(&state.in_service, source);
+            set_bit(&mut state.pending, source, pending);
+        });
+    }
+
+    /// Forgets all guest state, e.g. when the vm reboots. Host sources the guest
+    /// has not completed yet are completed on the host.
+    pub fn reset(&self) {
+        self.update(|state| {
+            for source in 1..VPLIC_NUM_SOURCES {
+                let claimed = get_bit(&state.pending, source) || get_bit(&state.in_service, source);
+                if claimed && get_bit(&self.passthrough, source) {
+                    plic::complete_host_irq(source);
+                }
+            }
+            let level = state.level;
+            *state = VPlicState::new(2 * self.num_vcpu);
+            state.level = level;
+            state.pending = level;
+        });
+    }
+
+    /// Applies `f` to the state and updates the guest external interrupt of every
+    /// vcpu accordingly.
+    fn update(&self, f: impl FnOnce(&mut VPlicState)) {
+        let lines = {
+            let mut state = self.state.lock();
+            f(&mut state);
+            (0..self.num_vcpu)
+                .filter(|vcpu_id| state.best_source(2 * vcpu_id + 1).is_some())
+                .fold(0u64, |lines, vcpu_id| lines | 1 << vcpu_id)
+        };
+        let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(self.vm_id) };
+        for (vcpu_id, ctrl) in vm.vcpu_ctrls.iter().enumerate() {
+            let line = lines & (1 << vcpu_id) != 0;
+            if ctrl.external_pending() != line {
+                ctrl.set_external_pending(line);
+                if line {
+                    sched::wake_vcpu((self.vm_id, vcpu_id));
+                }
+            }
+        }
+    }
+
+    fn claim(&self, context: usize) -> usize {
+        let mut source = 0;
+        self.update(|state| {
+            if let Some(best) = state.best_source(context) {
+                set_bit(&mut state.pending, best, false);
+                set_bit(&mut state.in_service, best, true);
+                source = best;
+            }
+        });
+        source
+    }
+
+    fn complete(&self, context: usize, source: usize) {
+        if source == 0 || source >= VPLIC_NUM_SOURCES {
+            return;
+        }
+        self.update(|state| {
+            if !get_bit(&state.contexts[context].enable, source)
+                || !get_bit(&state.in_service, source)
+            {
+                return;
+            }
+            set_bit(&mut state.in_service, source, false);
+            if get_bit(&self.passthrough, source) {
+                plic::complete_host_irq(source);
+            } else if get_bit(&state.level, source) {
+                set_bit(&mut state.pending, source, true);
+            }
+        });
+    }
+}
+
+impl VPlicState {
+    fn new(num_contexts: usize) -> Self {
+        Self {
+            priority: vec![0; VPLIC_NUM_SOURCES],
+            pending: [0; WORDS],
+            in_service: [0; WORDS],
+            level: [0; WORDS],
+            contexts: vec![
+                Context {
+                    enable: [0; WORDS],
+                    threshold: 0,
+                };
+                num_contexts
+            ],
+        }
+    }
+
+    /// The source `context` would claim, the highest priority one with the
+    /// lowest id among those pending and enabled above the threshold.
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let ctx = &self.contexts[context];
+        let mut best: Option<usize> = None;
+        for word in 0..WORDS {
+            let mut bits = self.pending[word] & ctx.enable[word];
+            while bits != 0 {
+                let source = word * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let priority = self.priority[source];
+                if priority > ctx.threshold && best.is_none_or(|b| priority > self.priority[b]) {
+                    best = Some(source);
+                }
+            }
+        }
+        best
+    }
+}
+
+impl MmioDevice for VPlic {
+    fn size(&self) -> usize {
+        align_up(
+            CONTEXT_BASE + 2 * self.num_vcpu * CONTEXT_STRIDE,
+            PAGE_SIZE_4K,
+        )
+    }
+
+    fn read(&self, offset: usize, _width: usize) -> usize {
+        // registers are 32 bits wide
+        let offset = offset & !0b11;
+        if offset >= CONTEXT_BASE {
+            let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+            return match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                CONTEXT_THRESHOLD => self.state.lock().contexts[context].threshold as usize,
+                CONTEXT_CLAIM => self.claim(context),
+                _ => 0,
+            };
+        }
+        let state = self.state.lock();
+        let value = if offset >= ENABLE_BASE {
+            let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+            let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+            state
+                .contexts
+                .get(context)
+                .map_or(0, |ctx| ctx.enable[word])
+        } else if offset >= PENDING_BASE {
+            state
+                .pending
+                .get((offset - PENDING_BASE) / 4)
+                .copied()
+                .unwrap_or(0)
+        } else {
+            state.priority[(offset - PRIORITY_BASE) / 4]
+        };
+        value as usize
+    }
+
+    fn write(&self, offset: usize, _width: usize, value: usize) {
+        let offset = offset & !0b11;
+        let value = value as u32;
+        if offset >= CONTEXT_BASE {
+            let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+            match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                CONTEXT_THRESHOLD => self.update(|state| {
+                    state.contexts[context].threshold = value & PRIORITY_MASK;
+                }),
+                CONTEXT_CLAIM => self.complete(context, value as usize),
+                _ => {}
+            }
+        } else if offset >= ENABLE_BASE {
+            let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+            let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+            if context < 2 * self.num_vcpu {
+                self.update(|state| {
+                    // source 0 does not exist
+                    let value = if word == 0 { value & !1 } else { value };
+                    state.contexts[context].enable[word] = value;
+                });
+            }
+        } else if offset >= PENDING_BASE {
+            // pending bits are read-only
+        } else {
+            let source = (offset - PRIORITY_BASE) / 4;
+            if source != 0 {
+                self.update(|state| state.priority[source] = value & PRIORITY_MASK);
+            }
+        }
+    }
+}
+
+/// A device interrupt line wired to a source of a virtual PLIC.
+pub struct IrqLine {
+    plic: Arc<VPlic>,
+    source: usize,
+}
+
+impl IrqLine {
+    pub fn new(plic: Arc<VPlic>, source: usize) -> Self {
+        Self { plic, source }
+    }
+
+    pub fn set_level(&self, level: bool) {
+        self.plic.set_level(self.source, level);
+    }
+}
+
+/// Whether `source` exists on a virtual PLIC.
+pub fn is_valid_source(source: usize) -> bool {
+    source != 0 && source < VPLIC_NUM_SOURCES
+}
+
+fn get_bit(bits: &[u32; WORDS], source: usize) -> bool {
+    bits[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32; WORDS], source: usize, value: bool) {
+    if value {
+        bits[source / 32] |= 1 << (source % 32);
+    } else {
+        bits[source / 32] &= !(1 << (source % 32));
+    }
+}
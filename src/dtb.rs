@@ -1,25 +1,55 @@
-use arrayvec::ArrayVec;
+use arrayvec::{ArrayString, ArrayVec};
+use fdt::node::FdtNode;
 use fdt::Fdt;
-use log::{debug, info};
+use log::{debug, info, warn};
+
+use crate::config::TIMEBASE_FREQUENCY;
+
+/// `interrupts-extended` cause of the S-mode external interrupt of a hart.
+const IRQ_S_EXT: usize = 9;
 
 #[derive(Clone, Debug)]
 pub struct Device {
     pub base_address: usize,
     pub size: usize,
+    /// PLIC source of the device's interrupt.
+    pub irq: Option<usize>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Hart {
     pub hartid: usize,
     pub plic_context: usize,
+    /// `riscv,isa`, e.g. "rv64imafdch_zicsr_zifencei".
+    pub isa: ArrayString<256>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub size: usize,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct MachineMeta {
     pub phys_mem_start: usize,
     pub phys_mem_size: usize,
+    /// Frequency of the `time` CSR.
+    pub timebase_frequency: usize,
     pub harts: ArrayVec<Hart, 16>,
     pub virtio: ArrayVec<Device, 16>,
+    pub plic: Option<Device>,
+    /// Number of PLIC interrupt sources, `riscv,ndev`.
+    pub plic_num_sources: usize,
+    pub clint: Option<Device>,
+    pub uart: Option<Device>,
+    pub rtc: Option<Device>,
+    /// ECAM window of the PCIe host bridge.
+    pub pci: Option<Device>,
+    /// `/reserved-memory` nodes and `/memreserve/` entries.
+    pub reserved_memory: ArrayVec<MemoryRegion, 16>,
+    /// `/chosen/bootargs`.
+    pub bootargs: ArrayString<1024>,
 }
 
 impl MachineMeta {
@@ -31,25 +61,161 @@ impl MachineMeta {
             meta.phys_mem_start = region.starting_address as usize;
             meta.phys_mem_size = region.size.unwrap();
         }
+        meta.timebase_frequency = timebase_frequency(&fdt).unwrap_or_else(|| {
+            warn!(
+                "[Hypervisor] no timebase-frequency, using {}",
+                TIMEBASE_FREQUENCY
+            );
+            TIMEBASE_FREQUENCY
+        });
         for cpu in fdt.cpus() {
-            meta.harts.push(Hart {
+            let isa = cpu
+                .property("riscv,isa")
+                .and_then(|isa| isa.as_str())
+                .unwrap_or_default();
+            let hart = Hart {
                 hartid: cpu.ids().first(),
-                // TODO: get plic context
-                plic_context: 0,
-            });
+                // qemu virt layout, used if the PLIC does not say otherwise
+                plic_context: 2 * cpu.ids().first() + 1,
+                isa: truncated("riscv,isa", isa),
+            };
+            if meta.harts.try_push(hart).is_err() {
+                warn!(
+                    "[Hypervisor] too many harts, ignoring hart {}",
+                    cpu.ids().first()
+                );
+            }
         }
         for node in fdt.find_all_nodes("/soc/virtio_mmio") {
-            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
-                let paddr = reg.starting_address as usize;
-                let size = reg.size.unwrap();
-                meta.virtio.push(Device {
-                    base_address: paddr,
-                    size,
-                })
+            if let Some(dev) = parse_device(&node) {
+                if meta.virtio.try_push(dev).is_err() {
+                    warn!(
+                        "[Hypervisor] too many virtio devices, ignoring {}",
+                        node.name
+                    );
+                }
+            }
+        }
+        if let Some(node) = fdt.find_node("/soc/plic") {
+            meta.plic = parse_device(&node);
+            meta.plic_num_sources = node
+                .property("riscv,ndev")
+                .and_then(|ndev| ndev.as_usize())
+                // the most the PLIC allows
+                .unwrap_or(1023);
+            parse_plic_contexts(&fdt, &node, &mut meta.harts);
+        }
+        meta.clint = fdt.find_node("/soc/clint").and_then(|n| parse_device(&n));
+        meta.uart = fdt.find_node("/soc/serial").and_then(|n| parse_device(&n));
+        meta.rtc = fdt.find_node("/soc/rtc").and_then(|n| parse_device(&n));
+        meta.pci = fdt.find_node("/soc/pci").and_then(|n| parse_device(&n));
+
+        for reservation in fdt.memory_reservations() {
+            meta.add_reserved_memory(MemoryRegion {
+                start: reservation.address() as usize,
+                size: reservation.size(),
+            });
+        }
+        if let Some(reserved) = fdt.find_node("/reserved-memory") {
+            for node in reserved.children() {
+                if let Some(dev) = parse_device(&node) {
+                    meta.add_reserved_memory(MemoryRegion {
+                        start: dev.base_address,
+                        size: dev.size,
+                    });
+                }
             }
         }
+        if let Some(bootargs) = fdt.chosen().bootargs() {
+            meta.bootargs = truncated("bootargs", bootargs);
+        }
         meta
     }
+
+    fn add_reserved_memory(&mut self, region: MemoryRegion) {
+        if self.reserved_memory.try_push(region).is_err() {
+            warn!(
+                "[Hypervisor] too many reserved memory regions, ignoring {:#x?}",
+                region
+            );
+        }
+    }
+}
+
+/// `timebase-frequency` of the first cpu node, or of `/cpus` which cpu nodes
+/// inherit it from.
+fn timebase_frequency(fdt: &Fdt) -> Option<usize> {
+    fdt.cpus()
+        .next()
+        .and_then(|cpu| cpu.property("timebase-frequency"))
+        .or_else(|| fdt.find_node("/cpus")?.property("timebase-frequency"))
+        .and_then(|freq| freq.as_usize())
+}
+
+/// The first `reg` range and interrupt of a node.
+fn parse_device(node: &FdtNode) -> Option<Device> {
+    let reg = node.reg()?.next()?;
+    let irq = node
+        .property("interrupts")
+        .and_then(|irqs| irqs.value.get(..4))
+        .map(|irq| u32::from_be_bytes(irq.try_into().unwrap()) as usize);
+    Some(Device {
+        base_address: reg.starting_address as usize,
+        size: reg.size.unwrap_or(0),
+        irq,
+    })
+}
+
+/// Fills in the PLIC context of each hart from the PLIC's `interrupts-extended`,
+/// which lists one `<&cpu_intc cause>` pair per context.
+fn parse_plic_contexts(fdt: &Fdt, plic: &FdtNode, harts: &mut [Hart]) {
+    let Some(contexts) = plic.property("interrupts-extended") else {
+        return;
+    };
+    let cells = contexts
+        .value
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()));
+    let pairs = cells.clone().step_by(2).zip(cells.skip(1).step_by(2));
+    for (context, (phandle, cause)) in pairs.enumerate() {
+        if cause as usize != IRQ_S_EXT {
+            continue;
+        }
+        let Some(hartid) = intc_hartid(fdt, phandle) else {
+            warn!("[Hypervisor] no hart for PLIC context {}", context);
+            continue;
+        };
+        if let Some(hart) = harts.iter_mut().find(|hart| hart.hartid == hartid) {
+            debug!("[Hypervisor] hart {} PLIC context {}", hartid, context);
+            hart.plic_context = context;
+        }
+    }
+}
+
+/// The hart whose local interrupt controller has `phandle`.
+fn intc_hartid(fdt: &Fdt, phandle: u32) -> Option<usize> {
+    fdt.find_node("/cpus")?
+        .children()
+        .find(|cpu| {
+            cpu.children().any(|intc| {
+                intc.property("phandle")
+                    .and_then(|p| p.as_usize())
+                    .is_some_and(|p| p == phandle as usize)
+            })
+        })?
+        .property("reg")?
+        .as_usize()
+}
+
+fn truncated<const N: usize>(name: &str, s: &str) -> ArrayString<N> {
+    let mut end = s.len().min(N);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end < s.len() {
+        warn!("[Hypervisor] {} truncated to {} bytes", name, end);
+    }
+    ArrayString::from(&s[..end]).unwrap()
 }
 
 pub fn parse_dtb(dtb: usize) {
@@ -4,6 +4,7 @@ pub enum HypervisorError {
     NoMemory,
     NotMapped,
     AlreadyMapped,
+    GuestMemoryFault,
 }
 
 pub type HypervisorResult<T> = Result<T, HypervisorError>;
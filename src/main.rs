@@ -9,13 +9,16 @@ mod allocator;
 mod config;
 mod console;
 mod csr;
+mod device;
 mod dtb;
 mod error;
 mod lang_items;
 mod logging;
 mod mem;
 mod pcpu;
+mod plic;
 mod sbi;
+mod sched;
 mod trap;
 mod vm;
 
@@ -47,7 +50,7 @@ pub unsafe extern "C" fn start() -> ! {
 pub fn hmain(hart_id: usize, dtb: usize) -> ! {
     clear_bss();
     logging::init();
-    trap::set_hypervisor_trap_entry();
+    trap::set_hypervisor_trap_entry(trap::boot_trap_stack_top());
     info!("[Hypervisor] Hello, world!");
     info!("[HyperVisor] hart_id: {}, dtb: {:#x}", hart_id, dtb);
 
@@ -65,20 +68,54 @@ pub fn hmain(hart_id: usize, dtb: usize) -> ! {
     mem::init_hypervisor_page_table(&machine_meta);
     mem::enable_mmu();
     allocator::heap_test();
+    mem::guest_page_table_test();
 
     pcpu::init_pcpus(hart_id, &machine_meta);
 
+    // the guest device trees depend on the extensions detected here
+    csr::init_csrs();
+
     vm::init_vms(&machine_meta);
     vm::bind_vcpus();
+    plic::init_host_plic(&machine_meta);
+
+    pcpu::start_secondary_cpus(hart_id, _secondary_start as usize);
 
+    let pcpu = pcpu::this_cpu();
+    pcpu.run();
+
+    pcpu::exit_this_cpu()
+}
+
+/// Entry of the secondary harts started through SBI HSM.
+///
+/// # Safety
+///
+/// Only to be jumped to by the SBI with `a1` the stack top of the hart's pcpu.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _secondary_start() -> ! {
+    // PC = _secondary_start
+    // a0 = hartid
+    // a1 = opaque, the stack top of this hart's pcpu
+    core::arch::naked_asm!(
+        "mv sp, a1", // setup pcpu stack
+        "call secondary_main",
+    )
+}
+
+#[no_mangle]
+pub fn secondary_main(hart_id: usize) -> ! {
+    // also sets the trap entry, on this hart's own trap stack
+    pcpu::setup_this_cpu(hart_id);
+    mem::enable_mmu();
     csr::init_csrs();
+    info!("[HyperVisor] secondary hart {} started", hart_id);
 
     let pcpu = pcpu::this_cpu();
     pcpu.run();
 
-    info!("[HyperVisor] exited");
-    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
-    unreachable!()
+    pcpu::exit_this_cpu()
 }
 
 /// clear BSS segment
@@ -37,8 +37,20 @@ pub const fn is_aligned(addr: usize, align: usize) -> bool {
     align_offset(addr, align) == 0
 }
 
+/// An address type page tables can translate.
+pub trait MemoryAddr: Copy + From<usize> {
+    fn as_usize(self) -> usize;
+}
+
 macro_rules! impl_common_addr_methods {
     ($t: ty) => {
+        impl MemoryAddr for $t {
+            #[inline]
+            fn as_usize(self) -> usize {
+                self.0
+            }
+        }
+
         impl $t {
             pub const fn new(addr: usize) -> Self {
                 Self(addr)
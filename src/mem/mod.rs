@@ -1,6 +1,7 @@
 pub mod addr;
 mod guest_page_table;
 pub mod page_table;
+pub mod paging;
 pub mod pte;
 pub mod region;
 
@@ -16,8 +17,9 @@ use riscv::register::satp;
 pub fn enable_mmu() {
     // host virtual address -> host physical address
     let page_table_root = HYPERVISOR_PAGE_TABLE.lock().root_paddr().as_usize();
+    let mode = satp::Mode::try_from(PageTable::MODE as u8).unwrap();
     unsafe {
-        satp::set(satp::Mode::Sv39, 0, page_table_root >> 12);
+        satp::set(mode, 0, page_table_root >> 12);
         riscv::asm::sfence_vma_all();
     }
 }
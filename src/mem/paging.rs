@@ -0,0 +1,472 @@
+use core::marker::PhantomData;
+
+use crate::{
+    allocator::frame::PHYS_FRAME_ALLOCATOR,
+    config::PAGE_SIZE_4K,
+    error::{HypervisorError, HypervisorResult},
+};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{
+    addr::{align_down, HostPhysAddr, MemoryAddr},
+    pte::{PTEFlags, PageTableEntry},
+};
+
+/// Entries of a non-root table.
+const PTE_COUNT: usize = 512;
+
+/// A RISC-V paging mode, the root table is level 0.
+pub trait PagingMode {
+    const LEVELS: usize;
+    /// Entries of the root table, the G-stage x4 modes widen it to 4 pages.
+    const ROOT_PTE_COUNT: usize;
+    /// Value of the MODE field of `satp` or `hgatp` selecting this mode.
+    const MODE: usize;
+    /// Translated addresses are below this, only the lower half of the S-stage
+    /// address spaces is used.
+    const VADDR_LIMIT: usize;
+
+    /// Flushes the translations of this mode cached by this hart, other harts
+    /// must be fenced separately.
+    fn flush_tlb();
+}
+
+pub struct Sv39;
+pub struct Sv48;
+pub struct Sv39x4;
+pub struct Sv48x4;
+pub struct Sv57x4;
+
+impl PagingMode for Sv39 {
+    const LEVELS: usize = 3;
+    const ROOT_PTE_COUNT: usize = PTE_COUNT;
+    const MODE: usize = 8;
+    const VADDR_LIMIT: usize = 1 << 38;
+
+    fn flush_tlb() {
+        sfence_vma();
+    }
+}
+
+impl PagingMode for Sv48 {
+    const LEVELS: usize = 4;
+    const ROOT_PTE_COUNT: usize = PTE_COUNT;
+    const MODE: usize = 9;
+    const VADDR_LIMIT: usize = 1 << 47;
+
+    fn flush_tlb() {
+        sfence_vma();
+    }
+}
+
+impl PagingMode for Sv39x4 {
+    const LEVELS: usize = 3;
+    const ROOT_PTE_COUNT: usize = PTE_COUNT * 4;
+    const MODE: usize = 8;
+    const VADDR_LIMIT: usize = 1 << 41;
+
+    fn flush_tlb() {
+        hfence_gvma();
+    }
+}
+
+impl PagingMode for Sv48x4 {
+    const LEVELS: usize = 4;
+    const ROOT_PTE_COUNT: usize = PTE_COUNT * 4;
+    const MODE: usize = 9;
+    const VADDR_LIMIT: usize = 1 << 50;
+
+    fn flush_tlb() {
+        hfence_gvma();
+    }
+}
+
+impl PagingMode for Sv57x4 {
+    const LEVELS: usize = 5;
+    const ROOT_PTE_COUNT: usize = PTE_COUNT * 4;
+    const MODE: usize = 10;
+    const VADDR_LIMIT: usize = 1 << 59;
+
+    fn flush_tlb() {
+        hfence_gvma();
+    }
+}
+
+/// A page table of paging mode `M` translating addresses of type `VA`. Tables
+/// are accessed through their physical address, which the hypervisor maps
+/// identically.
+pub struct GenericPageTable<M: PagingMode, VA: MemoryAddr> {
+    root_paddr: HostPhysAddr,
+    /// Every table, the root first.
+    intrm_tables: Vec<HostPhysAddr>,
+    _phantom: PhantomData<(M, VA)>,
+}
+
+impl<M: PagingMode, VA: MemoryAddr> GenericPageTable<M, VA> {
+    /// Value of the MODE field of `satp` or `hgatp` for this page table.
+    pub const MODE: usize = M::MODE;
+    /// Pages of the root table.
+    const ROOT_FRAMES: usize = M::ROOT_PTE_COUNT / PTE_COUNT;
+
+    pub const fn empty() -> Self {
+        Self {
+            root_paddr: HostPhysAddr::new(usize::MAX),
+            intrm_tables: Vec::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn try_new() -> HypervisorResult<Self> {
+        let root_size = Self::ROOT_FRAMES * PAGE_SIZE_4K;
+        let root_paddr = PHYS_FRAME_ALLOCATOR
+            .lock()
+            .alloc_frames(Self::ROOT_FRAMES, root_size)?;
+        unsafe { core::ptr::write_bytes(root_paddr.as_usize() as *mut u8, 0, root_size) };
+        Ok(Self {
+            root_paddr,
+            intrm_tables: vec![root_paddr],
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn root_paddr(&self) -> HostPhysAddr {
+        self.root_paddr
+    }
+
+    /// Removes all mappings and frees the intermediate tables, keeping the root.
+    pub fn clear(&mut self) {
+        for paddr in self.intrm_tables.drain(1..) {
+            PHYS_FRAME_ALLOCATOR.lock().dealloc_frames(paddr, 1);
+        }
+        unsafe {
+            core::ptr::write_bytes(
+                self.root_paddr.as_usize() as *mut u8,
+                0,
+                Self::ROOT_FRAMES * PAGE_SIZE_4K,
+            )
+        };
+        M::flush_tlb();
+    }
+
+    /// Maps a page of the size of a leaf at `level`.
+    fn map_page(
+        &mut self,
+        vaddr: VA,
+        paddr: HostPhysAddr,
+        level: usize,
+        flags: PTEFlags,
+    ) -> HypervisorResult<()> {
+        assert!(vaddr.as_usize() % Self::page_size(level) == 0);
+        assert!(paddr.is_aligned(Self::page_size(level)));
+        let pte = self.get_entry_mut(vaddr, level, true)?;
+        if pte.is_unused() {
+            *pte = PageTableEntry::new(paddr, flags);
+            Ok(())
+        } else {
+            Err(HypervisorError::AlreadyMapped)
+        }
+    }
+
+    /// Maps `num_pages` 4K pages, using larger pages where both addresses and
+    /// the remaining length allow it.
+    pub fn map_region(
+        &mut self,
+        vaddr: VA,
+        paddr: HostPhysAddr,
+        num_pages: usize,
+        flags: PTEFlags,
+    ) -> HypervisorResult<()> {
+        self.map_region_from_level(vaddr, paddr, num_pages, flags, 0)
+    }
+
+    /// Maps `num_pages` 4K pages with 4K leaves only, so that their permissions
+    /// can later be changed page by page.
+    pub fn map_region_4k(
+        &mut self,
+        vaddr: VA,
+        paddr: HostPhysAddr,
+        num_pages: usize,
+        flags: PTEFlags,
+    ) -> HypervisorResult<()> {
+        self.map_region_from_level(vaddr, paddr, num_pages, flags, M::LEVELS - 1)
+    }
+
+    /// Maps a region with leaves at `min_level` or below.
+    fn map_region_from_level(
+        &mut self,
+        vaddr: VA,
+        paddr: HostPhysAddr,
+        num_pages: usize,
+        flags: PTEFlags,
+        min_level: usize,
+    ) -> HypervisorResult<()> {
+        assert!(vaddr.as_usize() % PAGE_SIZE_4K == 0);
+        assert!(paddr.is_aligned(PAGE_SIZE_4K));
+        let size = Self::check_range(vaddr, num_pages)? - vaddr.as_usize();
+        let mut offset = 0;
+        while offset < size {
+            let level = (min_level..M::LEVELS)
+                .find(|level| {
+                    let page_size = Self::page_size(*level);
+                    (vaddr.as_usize() + offset) % page_size == 0
+                        && (paddr + offset).is_aligned(page_size)
+                        && size - offset >= page_size
+                })
+                .unwrap();
+            let vaddr = VA::from(vaddr.as_usize() + offset);
+            self.map_page(vaddr, paddr + offset, level, flags)?;
+            offset += Self::page_size(level);
+        }
+        Ok(())
+    }
+
+    pub fn query_page(&mut self, vpn: VA) -> HypervisorResult<(HostPhysAddr, PTEFlags)> {
+        assert_eq!(vpn.as_usize() & (PAGE_SIZE_4K - 1), 0);
+        let (pte, page_size) = self.find_leaf(vpn)?;
+        let offset = vpn.as_usize() & (page_size - 1);
+        Ok((pte.ppn() + offset, pte.flags()))
+    }
+
+    pub fn translate(&mut self, vaddr: VA) -> HypervisorResult<HostPhysAddr> {
+        let (pte, page_size) = self.find_leaf(vaddr)?;
+        if pte.is_valid() {
+            let offset = vaddr.as_usize() & (page_size - 1);
+            let paddr = pte.ppn().as_usize() + offset;
+            Ok(paddr.into())
+        } else {
+            Err(HypervisorError::NotMapped)
+        }
+    }
+
+    /// Removes the mappings of `num_pages` 4K pages from `vaddr`, splitting larger
+    /// pages only partly in the range, and frees the tables left empty. Only this
+    /// hart is fenced.
+    pub fn unmap_region(&mut self, vaddr: VA, num_pages: usize) -> HypervisorResult<()> {
+        assert!(vaddr.as_usize() % PAGE_SIZE_4K == 0);
+        let end = Self::check_range(vaddr, num_pages)?;
+        let result = self.update_leaves(self.root_paddr, 0, vaddr.as_usize(), end, &mut |pte| {
+            *pte = PageTableEntry::empty()
+        });
+        M::flush_tlb();
+        result
+    }
+
+    /// Changes the flags of `num_pages` 4K pages from `vaddr`, splitting larger
+    /// pages only partly in the range. Only this hart is fenced.
+    pub fn protect_region(
+        &mut self,
+        vaddr: VA,
+        num_pages: usize,
+        flags: PTEFlags,
+    ) -> HypervisorResult<()> {
+        assert!(vaddr.as_usize() % PAGE_SIZE_4K == 0);
+        // without R, W and X the entry would point to a table
+        assert!(flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X));
+        let end = Self::check_range(vaddr, num_pages)?;
+        let result = self.update_leaves(self.root_paddr, 0, vaddr.as_usize(), end, &mut |pte| {
+            *pte = PageTableEntry::new(pte.ppn(), flags)
+        });
+        M::flush_tlb();
+        result
+    }
+
+    /// Calls `f` with the address, physical address, size and flags of every
+    /// mapped page, in address order.
+    pub fn for_each_leaf(&self, mut f: impl FnMut(VA, HostPhysAddr, usize, PTEFlags)) {
+        self.visit_leaves(self.root_paddr, 0, 0, &mut f);
+    }
+
+    /// Size of the pages mapped by a leaf at `level`.
+    const fn page_size(level: usize) -> usize {
+        PAGE_SIZE_4K << (9 * (M::LEVELS - 1 - level))
+    }
+
+    /// Returns the end of `num_pages` 4K pages from `vaddr`, failing if they do not
+    /// fit the address space of the mode.
+    fn check_range(vaddr: VA, num_pages: usize) -> HypervisorResult<usize> {
+        match num_pages
+            .checked_mul(PAGE_SIZE_4K)
+            .and_then(|size| vaddr.as_usize().checked_add(size))
+        {
+            Some(end) if end <= M::VADDR_LIMIT => Ok(end),
+            _ => Err(HypervisorError::InvalidParam),
+        }
+    }
+
+    /// Index of the entry translating `vaddr` in a table at `level`.
+    fn index(vaddr: usize, level: usize) -> usize {
+        let len = if level == 0 {
+            M::ROOT_PTE_COUNT
+        } else {
+            PTE_COUNT
+        };
+        (vaddr / Self::page_size(level)) & (len - 1)
+    }
+
+    fn table_of_mut<'a>(&self, paddr: HostPhysAddr, level: usize) -> &'a mut [PageTableEntry] {
+        let len = if level == 0 {
+            M::ROOT_PTE_COUNT
+        } else {
+            PTE_COUNT
+        };
+        let ptr = paddr.as_usize() as _;
+        // as we did identical mapping, so vaddr = paddr
+        unsafe { core::slice::from_raw_parts_mut(ptr, len) }
+    }
+
+    /// Physical address of the table `entry` points to, creating it if asked.
+    fn next_table(
+        &mut self,
+        entry: &mut PageTableEntry,
+        create_if_absent: bool,
+    ) -> HypervisorResult<HostPhysAddr> {
+        if entry.is_unused() && create_if_absent {
+            let paddr = PHYS_FRAME_ALLOCATOR.lock().alloc_frames(1, PAGE_SIZE_4K)?;
+            unsafe { core::ptr::write_bytes(paddr.as_usize() as *mut u8, 0, PAGE_SIZE_4K) };
+            self.intrm_tables.push(paddr);
+            *entry = PageTableEntry::new(paddr, PTEFlags::V);
+        }
+        if entry.is_leaf() {
+            // covered by a larger page
+            Err(HypervisorError::AlreadyMapped)
+        } else if entry.is_valid() {
+            Ok(entry.ppn())
+        } else {
+            Err(HypervisorError::NotMapped)
+        }
+    }
+
+    /// Returns the entry at `level` for `vaddr`.
+    fn get_entry_mut(
+        &mut self,
+        vaddr: VA,
+        level: usize,
+        create_if_absent: bool,
+    ) -> HypervisorResult<&mut PageTableEntry> {
+        let mut table_paddr = self.root_paddr;
+        for parent_level in 0..level {
+            let table = self.table_of_mut(table_paddr, parent_level);
+            let pte = &mut table[Self::index(vaddr.as_usize(), parent_level)];
+            table_paddr = self.next_table(pte, create_if_absent)?;
+        }
+        let table = self.table_of_mut(table_paddr, level);
+        Ok(&mut table[Self::index(vaddr.as_usize(), level)])
+    }
+
+    /// Returns the leaf entry mapping `vaddr` and the size of its page, or the
+    /// unused 4K entry if there is a last level table.
+    fn find_leaf(&mut self, vaddr: VA) -> HypervisorResult<(&mut PageTableEntry, usize)> {
+        let mut table_paddr = self.root_paddr;
+        let mut level = 0;
+        loop {
+            let table = self.table_of_mut(table_paddr, level);
+            let pte = &mut table[Self::index(vaddr.as_usize(), level)];
+            if pte.is_leaf() || level == M::LEVELS - 1 {
+                return Ok((pte, Self::page_size(level)));
+            }
+            table_paddr = self.next_table(pte, false)?;
+            level += 1;
+        }
+    }
+
+    fn visit_leaves(
+        &self,
+        table_paddr: HostPhysAddr,
+        level: usize,
+        base: usize,
+        f: &mut dyn FnMut(VA, HostPhysAddr, usize, PTEFlags),
+    ) {
+        let page_size = Self::page_size(level);
+        let table = self.table_of_mut(table_paddr, level);
+        for (i, pte) in table.iter().enumerate() {
+            let vaddr = base + i * page_size;
+            if pte.is_leaf() {
+                f(vaddr.into(), pte.ppn(), page_size, pte.flags());
+            } else if pte.is_valid() {
+                self.visit_leaves(pte.ppn(), level + 1, vaddr, f);
+            }
+        }
+    }
+
+    /// Applies `f` to the leaves mapping `[start, end)` under the table at `level`,
+    /// fails if part of the range is not mapped.
+    fn update_leaves(
+        &mut self,
+        table_paddr: HostPhysAddr,
+        level: usize,
+        start: usize,
+        end: usize,
+        f: &mut dyn FnMut(&mut PageTableEntry),
+    ) -> HypervisorResult<()> {
+        let page_size = Self::page_size(level);
+        let table = self.table_of_mut(table_paddr, level);
+        let mut vaddr = start;
+        while vaddr < end {
+            let entry_start = align_down(vaddr, page_size);
+            let entry_end = (entry_start + page_size).min(end);
+            let pte = &mut table[Self::index(vaddr, level)];
+            if !pte.is_valid() {
+                return Err(HypervisorError::NotMapped);
+            }
+            if pte.is_leaf() {
+                if vaddr != entry_start || entry_end != entry_start + page_size {
+                    self.split_leaf(pte, level)?;
+                    continue;
+                }
+                f(pte);
+            } else {
+                let result = self.update_leaves(pte.ppn(), level + 1, vaddr, entry_end, f);
+                let next_table = self.table_of_mut(pte.ppn(), level + 1);
+                if next_table.iter().all(|entry| entry.is_unused()) {
+                    self.free_table(pte.ppn());
+                    *pte = PageTableEntry::empty();
+                }
+                result?;
+            }
+            vaddr = entry_end;
+        }
+        Ok(())
+    }
+
+    /// Replaces the leaf `pte` at `level` with a table of smaller leaves mapping the
+    /// same range with the same flags.
+    fn split_leaf(&mut self, pte: &mut PageTableEntry, level: usize) -> HypervisorResult<()> {
+        let paddr = PHYS_FRAME_ALLOCATOR.lock().alloc_frames(1, PAGE_SIZE_4K)?;
+        self.intrm_tables.push(paddr);
+        let page_size = Self::page_size(level + 1);
+        for (i, entry) in self.table_of_mut(paddr, level + 1).iter_mut().enumerate() {
+            *entry = PageTableEntry::new(pte.ppn() + i * page_size, pte.flags());
+        }
+        *pte = PageTableEntry::new(paddr, PTEFlags::V);
+        Ok(())
+    }
+
+    fn free_table(&mut self, paddr: HostPhysAddr) {
+        if let Some(i) = self.intrm_tables.iter().position(|table| *table == paddr) {
+            self.intrm_tables.swap_remove(i);
+            PHYS_FRAME_ALLOCATOR.lock().dealloc_frames(paddr, 1);
+        }
+    }
+}
+
+impl<M: PagingMode, VA: MemoryAddr> Drop for GenericPageTable<M, VA> {
+    fn drop(&mut self) {
+        for (i, paddr) in self.intrm_tables.iter().enumerate() {
+            let num_frames = if i == 0 { Self::ROOT_FRAMES } else { 1 };
+            PHYS_FRAME_ALLOCATOR
+                .lock()
+                .dealloc_frames(*paddr, num_frames);
+        }
+    }
+}
+
+fn sfence_vma() {
+    riscv::asm::sfence_vma_all();
+}
+
+fn hfence_gvma() {
+    unsafe {
+        core::arch::asm!("hfence.gvma");
+    }
+}
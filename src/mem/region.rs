@@ -6,24 +6,24 @@ use crate::mem::pte::PTEFlags;
 use log::{debug, info};
 
 pub fn map_mmio_regions(meta: &MachineMeta) {
-    for virt_dev in meta.virtio.iter() {
+    for dev in meta.virtio.iter().chain(meta.plic.iter()) {
         let pte_flags = PTEFlags::V | PTEFlags::R | PTEFlags::W;
         info!(
             "[Hypervisor] map region mmio: [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
-            virt_dev.base_address,
-            virt_dev.base_address + virt_dev.size,
-            virt_dev.base_address,
-            virt_dev.base_address + virt_dev.size,
+            dev.base_address,
+            dev.base_address + dev.size,
+            dev.base_address,
+            dev.base_address + dev.size,
             pte_flags
         );
-        assert_eq!(virt_dev.base_address % PAGE_SIZE_4K, 0);
-        assert_eq!(virt_dev.size % PAGE_SIZE_4K, 0);
+        assert_eq!(dev.base_address % PAGE_SIZE_4K, 0);
+        assert_eq!(dev.size % PAGE_SIZE_4K, 0);
         HYPERVISOR_PAGE_TABLE
             .lock()
             .map_region(
-                virt_dev.base_address.into(),
-                virt_dev.base_address.into(),
-                virt_dev.size / PAGE_SIZE_4K,
+                dev.base_address.into(),
+                dev.base_address.into(),
+                dev.size / PAGE_SIZE_4K,
                 pte_flags,
             )
             .expect("should work fine");
@@ -52,7 +52,7 @@ pub fn map_hypervisor_image() {
     );
     HYPERVISOR_PAGE_TABLE
         .lock()
-        .map_region(
+        .map_region_4k(
             stext.into(),
             stext.into(),
             (etext - stext) / PAGE_SIZE_4K,
@@ -78,7 +78,7 @@ pub fn map_hypervisor_image() {
     );
     HYPERVISOR_PAGE_TABLE
         .lock()
-        .map_region(
+        .map_region_4k(
             srodata.into(),
             srodata.into(),
             (erodata - srodata) / PAGE_SIZE_4K,
@@ -1,69 +1,323 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use alloc::vec::Vec;
-use log::{debug, info};
+use log::{debug, info, warn};
 use riscv::register::sstatus;
+use sbi_spec::hsm::hart_state;
 use spin::{Mutex, Once};
 
 use crate::{
     allocator::PHYS_FRAME_ALLOCATOR,
-    config::{PAGE_SIZE_4K, PCPU_STACK_SIZE},
+    config::{PAGE_SIZE_4K, PCPU_STACK_SIZE, SCHED_TIME_SLICE_MS, TRAP_STACK_SIZE},
     csr,
     dtb::MachineMeta,
     error::HypervisorResult,
-    mem::{HostPhysAddr, HostVirtAddr},
-    sbi,
-    vm::{VCpu, _vm_entry, GLOBAL_VMS},
+    mem::{align_up, GuestPageTable, GuestPhysAddr, HostPhysAddr, HostVirtAddr},
+    plic, sbi,
+    sched::{RunQueue, VCpuRef},
+    trap,
+    vm::{self, MmioAccess, VCpu, _vm_entry, GLOBAL_VMS, VM},
 };
 
 pub static GLOBAL_PCPUS: Once<Vec<PCpu>> = Once::new();
+/// Number of pcpus which still have vcpus to run.
+static RUNNING_PCPUS: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug)]
 pub struct PCpu {
     pub hart_id: usize,
     pub stack_top: HostPhysAddr,
-    // vec of (vm_id, vcpu_id)
-    pub vcpus: Mutex<Vec<(usize, usize)>>,
+    /// Stack of the traps taken by the hypervisor itself on this hart.
+    pub trap_stack_top: HostPhysAddr,
+    /// `SCHED_TIME_SLICE_MS` in ticks of the `time` CSR.
+    pub time_slice: u64,
+    pub run_queue: Mutex<RunQueue>,
 }
 
 impl PCpu {
+    /// Schedules the vcpus bound to this pcpu until every one of them halts.
+    ///
+    /// Each vcpu runs for at most `SCHED_TIME_SLICE_MS`, or until the timer of a
+    /// blocked vcpu fires, before being preempted by the hypervisor timer, which is
+    /// shared with the virtual timer of the running vcpu unless the guest has its
+    /// own `vstimecmp` (Sstc).
     pub fn run(&self) {
-        let (vm_id, vcpu_id) = self.vcpus.lock()[0];
-        let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(vm_id) };
-        let mut vcpu = unsafe { vm.vcpus.get_unchecked(vcpu_id).lock() };
+        let vmid_bits = csr::Hgatp::vmid_bits();
+        let time_slice = self.time_slice;
+        let mut loaded_vm = None;
+        let mut loaded_vcpu: Option<VCpuRef> = None;
+        let mut armed_timer = u64::MAX;
+        loop {
+            // the host console does not interrupt, input is polled once per slice
+            vm::poll_host_input();
+            let now = riscv::register::time::read64();
+            let next = self.run_queue.lock().pick_next(now);
+            let Some((vm_id, vcpu_id)) = next else {
+                if self.run_queue.lock().is_finished() {
+                    break;
+                }
+                // nothing to run, sleep until a blocked vcpu's timer fires, we are
+                // kicked, or console input must be polled again
+                let deadline = self.run_queue.lock().next_deadline();
+                armed_timer = arm_timer(armed_timer, deadline.min(now + time_slice));
+                riscv::asm::wfi();
+                unsafe { riscv::register::sip::clear_ssoft() };
+                if riscv::register::sip::read().sext() {
+                    plic::handle_external_interrupt();
+                }
+                continue;
+            };
+            let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(vm_id) };
+            let ctrl = &vm.vcpu_ctrls[vcpu_id];
+            let mut vcpu = unsafe { vm.vcpus.get_unchecked(vcpu_id).lock() };
+            // must be visible before the vm state is checked, see `VM::stop_other_vcpus`
+            ctrl.set_running(true);
+            // a stopped vcpu may still be queued, e.g. after its vm rebooted
+            let stopped = ctrl.hart_state() == hart_state::STOPPED;
+            if let Some(exit) = vm.inactive_exit().or(stopped.then_some(VCpuExit::Yield)) {
+                ctrl.set_running(false);
+                drop(vcpu);
+                if exit == VCpuExit::Halt {
+                    info!("[Hypervisor] vm {} vcpu {} halted", vm_id, vcpu_id);
+                }
+                self.run_queue.lock().put_prev((vm_id, vcpu_id), exit);
+                continue;
+            }
+            let vcpu_switched = loaded_vcpu != Some((vm_id, vcpu_id));
+            if let Some(prev) = loaded_vcpu.filter(|_| vcpu_switched) {
+                save_vcpu_context(prev);
+            }
+            loaded_vcpu = Some((vm_id, vcpu_id));
+            ctrl.transit_hart_state(hart_state::SUSPENDED, hart_state::STARTED);
+            if !vcpu.context_live {
+                vcpu.restore_context();
+            }
 
-        let hstatus = csr::Hstatus::read();
-        vcpu.guest_cpu_state.hstatus = hstatus.bits();
+            if loaded_vm != Some(vm_id) {
+                switch_guest_page_table(vm, vmid_bits);
+                loaded_vm = Some(vm_id);
+            }
+            // vcpus of a vm share its VMID, and the guest only fences the hart a
+            // vcpu runs on, so VS-stage translations left by another vcpu, or by
+            // this one before it last ran elsewhere, must go
+            if vcpu_switched || vcpu.last_hart != Some(self.hart_id) {
+                hfence_vvma(None, None);
+            }
+            // G-stage flushes of the vm are requested per vcpu, so this pcpu may
+            // have missed those requested while the vcpu ran elsewhere
+            if vcpu.last_hart != Some(self.hart_id) {
+                hfence_gvma();
+                vcpu.last_hart = Some(self.hart_id);
+            }
 
-        let mut sstatus = csr::Sstatus::read();
-        sstatus.set_spp(true);
-        vcpu.guest_cpu_state.sstatus = sstatus.bits();
+            debug!("[Hypervisor] run vm {} vcpu {}", vm_id, vcpu_id);
+            let slice_end = now + time_slice;
+            let exit = loop {
+                // preempt the vcpu as soon as a blocked one's timer fires
+                let preempt_at = slice_end.min(self.run_queue.lock().next_deadline());
+                if csr::sstc_supported() {
+                    armed_timer = arm_timer(armed_timer, preempt_at);
+                } else {
+                    // a guest deadline already passed is delivered through `hvip`,
+                    // arming the timer at it again would trap before the guest
+                    // could take the interrupt
+                    let deadline = ctrl.timer_deadline();
+                    let timer_at = if deadline > riscv::register::time::read64() {
+                        preempt_at.min(deadline)
+                    } else {
+                        preempt_at
+                    };
+                    armed_timer = arm_timer(armed_timer, timer_at);
+                    inject_timer_interrupt(deadline);
+                }
+                if ctrl.take_ipi_pending() {
+                    inject_software_interrupt();
+                }
+                inject_external_interrupt(ctrl.external_pending());
+                sbi::flush_requested(ctrl);
+                let exit = run_vcpu(vm, &mut vcpu);
+                if riscv::register::sip::read().stimer() {
+                    // the timer fired, it must be re-armed to clear the pending bit
+                    armed_timer = u64::MAX;
+                }
+                // another vcpu shut down or is rebooting the vm
+                if let Some(exit) = vm.inactive_exit() {
+                    break exit;
+                }
+                match exit {
+                    VCpuExit::Resume if riscv::register::time::read64() < preempt_at => continue,
+                    VCpuExit::Resume => break VCpuExit::Yield,
+                    exit => break exit,
+                }
+            };
+            ctrl.set_running(false);
+            drop(vcpu);
+            if exit == VCpuExit::Halt {
+                info!("[Hypervisor] vm {} vcpu {} halted", vm_id, vcpu_id);
+            }
+            self.run_queue.lock().put_prev((vm_id, vcpu_id), exit);
+        }
+    }
 
-        vcpu.guest_cpu_state.sepc = vm.entry.as_usize();
+    /// Forces this pcpu to reschedule soon, e.g. after one of its vcpus was woken up.
+    pub fn kick(&self) {
+        if self.hart_id != this_cpu().hart_id {
+            sbi_rt::send_ipi(sbi_rt::HartMask::from_mask_base(1, self.hart_id));
+        }
+    }
+}
 
-        let gpt_root = vm.guest_page_table.root_paddr().as_usize();
-        let mut hgatp = csr::Hgatp::read();
-        hgatp.set_mode(csr::Mode::Sv39x4);
-        hgatp.set_ppn(gpt_root >> 12);
-        hgatp.write();
+/// Saves the hardware context of a vcpu which is no longer going to run on this
+/// pcpu, unless it was replaced since the vcpu last ran.
+fn save_vcpu_context((vm_id, vcpu_id): VCpuRef) {
+    let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(vm_id) };
+    let mut vcpu = vm.vcpus[vcpu_id].lock();
+    if vcpu.context_live {
+        vcpu.save_context();
+    }
+}
 
-        unsafe {
-            core::arch::asm!("hfence.gvma");
+/// Programs the physical timer to fire at `deadline` unless it already does.
+fn arm_timer(armed: u64, deadline: u64) -> u64 {
+    if armed != deadline {
+        sbi_rt::set_timer(deadline);
+    }
+    deadline
+}
+
+/// Makes the guest timer interrupt pending once the vcpu's deadline has passed.
+fn inject_timer_interrupt(deadline: u64) {
+    let mut hvip = csr::Hvip::read();
+    hvip.set_vs_timer_interrupt(riscv::register::time::read64() >= deadline);
+    hvip.write();
+}
+
+/// Makes the guest software interrupt pending, the guest clears it through `sip`.
+fn inject_software_interrupt() {
+    let mut hvip = csr::Hvip::read();
+    hvip.set_vs_software_interrupt(true);
+    hvip.write();
+}
+
+/// Mirrors the vcpu's external interrupt line from its virtual PLIC, the guest
+/// clears it by claiming the interrupt.
+fn inject_external_interrupt(pending: bool) {
+    let mut hvip = csr::Hvip::read();
+    hvip.set_vs_external_interrupt(pending);
+    hvip.write();
+}
+
+/// Points `hgatp` at the guest page table of `vm`, tagged with VMID `vm_id + 1`
+/// if this hart implements enough VMID bits.
+fn switch_guest_page_table(vm: &VM, vmid_bits: usize) {
+    let vmid = vm.vm_id + 1;
+    let vmid_supported = vmid < (1 << vmid_bits);
+    let gpt_root = vm.guest_page_table.lock().root_paddr().as_usize();
+    let mut hgatp = csr::Hgatp::read();
+    hgatp.set_mode(GuestPageTable::MODE.into());
+    hgatp.set_vmid(if vmid_supported { vmid } else { 0 });
+    hgatp.set_ppn(gpt_root >> 12);
+    hgatp.write();
+
+    if !vmid_supported {
+        // translations of different vms share VMID 0
+        hfence_gvma();
+    }
+}
+
+/// Flushes the G-stage translations of all VMIDs cached by this hart.
+pub fn hfence_gvma() {
+    unsafe {
+        core::arch::asm!("hfence.gvma");
+    }
+}
+
+/// Flushes the guest translations of the VMID in `hgatp` for the given guest
+/// virtual address and ASID, or for all of them if `None`.
+pub fn hfence_vvma(vaddr: Option<usize>, asid: Option<usize>) {
+    // `hfence.vvma rs1, rs2` is not accepted by the assembler without the H
+    // extension enabled, so it is encoded by hand
+    unsafe {
+        match (vaddr, asid) {
+            (None, None) => core::arch::asm!(".insn r 0x73, 0, 0x11, x0, x0, x0"),
+            (Some(vaddr), None) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x11, x0, {}, x0", in(reg) vaddr)
+            }
+            (None, Some(asid)) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x11, x0, x0, {}", in(reg) asid)
+            }
+            (Some(vaddr), Some(asid)) => {
+                core::arch::asm!(".insn r 0x73, 0, 0x11, x0, {}, {}", in(reg) vaddr, in(reg) asid)
+            }
         }
+    }
+}
+
+/// What the pcpu should do with a vcpu after handling its vm exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VCpuExit {
+    /// Re-enter the same vcpu.
+    Resume,
+    /// The vcpu's time slice is over, switch to the next one.
+    Yield,
+    /// The vcpu waits for an interrupt, switch to the next one.
+    Block,
+    /// The vcpu will never run again.
+    Halt,
+}
+
+/// Emulates a guest load or store which faulted in the G-stage because it targets
+/// an emulated device. Any other such fault is fatal to the vm.
+fn handle_guest_page_fault(vm: &VM, vcpu: &mut VCpu, stval: usize) -> VCpuExit {
+    // htval holds the guest physical address shifted right by 2
+    let gpa = GuestPhysAddr::from(csr::htval::read() << 2 | stval & 0b11);
+    let device = vm.mmio_bus.find(gpa);
+    // htinst may be left zero by the hart, decode the trapped instruction then
+    let htinst = csr::htinst::read();
+    let access = MmioAccess::from_htinst(htinst).or_else(|| {
+        let inst = vm::fetch_guest_instruction(vcpu, vcpu.guest_cpu_state.sepc).ok()?;
+        MmioAccess::from_instruction(vm::decode(inst)?, vm::instruction_len(inst as u16))
+    });
+    let (Some((device, offset)), Some(access)) = (device, access) else {
+        warn!(
+            "[Hypervisor] vm {} vcpu {} faulted on {:?}, sepc: {:#x}, htinst: {:#x}",
+            vm.vm_id, vcpu.vcpu_id, gpa, vcpu.guest_cpu_state.sepc, htinst
+        );
+        vm.shutdown(vcpu.vcpu_id);
+        return VCpuExit::Halt;
+    };
 
-        info!("[Hypervisor] run vcpu: {:?}", vcpu_id);
-        while !run_vcpu(&mut vcpu) {}
+    let gprs = &mut vcpu.guest_cpu_state.gprs;
+    if access.is_write {
+        device.write(offset, access.width, gprs[access.reg] & access.mask());
+    } else {
+        let value = device.read(offset, access.width);
+        // writes to x0 are discarded
+        if access.reg != 0 {
+            gprs[access.reg] = access.extend(value);
+        }
     }
+    vcpu.guest_cpu_state.sepc += access.inst_len;
+    VCpuExit::Resume
 }
 
 #[no_mangle]
-fn run_vcpu(vcpu: &mut VCpu) -> bool {
+fn run_vcpu(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
     unsafe {
         _vm_entry(vcpu);
     }
 
-    vmexit_handler(vcpu)
+    if csr::sstc_supported() {
+        // the guest may have written `stimecmp` without trapping, the scheduler
+        // still needs its deadline to wake the vcpu up from `wfi`
+        let deadline = vcpu.host_time(csr::vstimecmp::read() as u64);
+        vm.vcpu_ctrls[vcpu.vcpu_id].set_timer_deadline(deadline);
+    }
+
+    vmexit_handler(vm, vcpu)
 }
 
-fn vmexit_handler(vcpu: &mut VCpu) -> bool {
+fn vmexit_handler(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
     let scause = csr::Scause::read();
     debug!("[Hypervisor] scause: {:?}", scause.cause());
     let stval = riscv::register::stval::read();
@@ -78,34 +332,20 @@ fn vmexit_handler(vcpu: &mut VCpu) -> bool {
                 csr::htval::read(),
                 csr::htinst::read(),
             );
-            let a7 = vcpu.guest_cpu_state.gprs[17];
-            sbi::handle_sbi_call(vcpu);
             vcpu.guest_cpu_state.sepc += 4;
-            if a7 == 8 || a7 == sbi_spec::srst::EID_SRST {
-                info!("[Hypervisor] Shutdown vm normally!");
-                return true;
-            }
-            return false;
+            sbi::handle_sbi_call(vm, vcpu)
         }
-        csr::Trap::Exception(csr::Exception::LoadGuestPageFault) => {
+        csr::Trap::Exception(csr::Exception::LoadGuestPageFault)
+        | csr::Trap::Exception(csr::Exception::StoreGuestPageFault) => {
             debug!(
-                "LoadGuestPageFault: stval: {:#x}, sepc: {:#x}, htval: {:#x}, htinst: {:#x}",
-                riscv::register::stval::read(),
-                vcpu.guest_cpu_state.sepc,
-                csr::htval::read(),
-                csr::htinst::read(),
-            );
-        }
-        csr::Trap::Exception(csr::Exception::StoreGuestPageFault) => {
-            debug!(
-                "StoreGuestPageFault: stval: {:#x}, sepc: {:#x}, htval: {:#x}, htinst: {:#x}",
+                "{:?}: stval: {:#x}, sepc: {:#x}, htval: {:#x}, htinst: {:#x}",
+                scause.cause(),
                 riscv::register::stval::read(),
                 vcpu.guest_cpu_state.sepc,
                 csr::htval::read(),
                 csr::htinst::read(),
             );
-            vcpu.guest_cpu_state.sepc += 4;
-            return false;
+            handle_guest_page_fault(vm, vcpu, stval)
         }
         csr::Trap::Interrupt(csr::Interrupt::SupervisorTimer) => {
             debug!(
@@ -115,14 +355,40 @@ fn vmexit_handler(vcpu: &mut VCpu) -> bool {
                 csr::htval::read(),
                 csr::htinst::read(),
             );
-            let mut hvip = csr::Hvip::read();
-            hvip.set_vs_timer_interrupt(true);
-            hvip.write();
-            unsafe {
-                riscv::register::sie::clear_stimer();
+            // the scheduler re-arms the timer and injects the guest timer interrupt
+            VCpuExit::Resume
+        }
+        csr::Trap::Interrupt(csr::Interrupt::SupervisorSoft) => {
+            // kicked by another pcpu, the scheduler will pick up the change
+            unsafe { riscv::register::sip::clear_ssoft() };
+            VCpuExit::Resume
+        }
+        csr::Trap::Exception(csr::Exception::VirtualInstruction) => {
+            // stval holds the instruction unless the hart leaves it zero
+            let inst = match stval {
+                0 => vm::fetch_guest_instruction(vcpu, vcpu.guest_cpu_state.sepc).ok(),
+                _ => Some(stval as u32),
+            };
+            if let Some(vm::Instruction::Wfi) = inst.and_then(vm::decode) {
+                vcpu.guest_cpu_state.sepc += 4;
+                let ctrl = &vm.vcpu_ctrls[vcpu.vcpu_id];
+                if riscv::register::time::read64() >= ctrl.timer_deadline()
+                    || ctrl.ipi_pending()
+                    || ctrl.external_pending()
+                {
+                    return VCpuExit::Resume;
+                }
+                return VCpuExit::Block;
             }
-            // vcpu.guest_cpu_state.sepc += 4;
-            return false;
+            warn!(
+                "[Hypervisor] vm {} vcpu {} executed unsupported virtual instruction {:#x?}, sepc: {:#x}",
+                vm.vm_id,
+                vcpu.vcpu_id,
+                inst.map(|inst| (inst, vm::decode(inst))),
+                vcpu.guest_cpu_state.sepc
+            );
+            vm.shutdown(vcpu.vcpu_id);
+            VCpuExit::Halt
         }
         csr::Trap::Interrupt(csr::Interrupt::SupervisorExternal) => {
             debug!(
@@ -132,16 +398,34 @@ fn vmexit_handler(vcpu: &mut VCpu) -> bool {
                 csr::htval::read(),
                 csr::htinst::read(),
             );
-            let mut hvip = csr::Hvip::read();
-            hvip.set_vs_external_interrupt(true);
-            hvip.write();
-            unsafe {
-                riscv::register::sie::clear_sext();
-            }
-            // vcpu.guest_cpu_state.sepc += 4;
-            return false;
+            // forwarded to the virtual PLIC of the vm owning the source
+            plic::handle_external_interrupt();
+            VCpuExit::Resume
         }
-        _ => {
+        csr::Trap::Exception(exception) => {
+            // raised by the guest, e.g. fetching from unmapped guest memory, so
+            // only its vm goes down
+            let (htval, htinst) = (csr::htval::read(), csr::htinst::read());
+            // a fault reading the stack overwrites the trap CSRs
+            let sp = vcpu.guest_cpu_state.gprs.sp();
+            let stack: Vec<u64> = (0..4)
+                .map_while(|i| vm::read_guest(vcpu, sp + i * 8).ok())
+                .collect();
+            warn!(
+                "[Hypervisor] vm {} vcpu {} took unhandled {:?}, stval: {:#x}, sepc: {:#x}, htval: {:#x}, htinst: {:#x}, stack: {:#x?}",
+                vm.vm_id,
+                vcpu.vcpu_id,
+                exception,
+                stval,
+                vcpu.guest_cpu_state.sepc,
+                htval,
+                htinst,
+                stack,
+            );
+            vm.shutdown(vcpu.vcpu_id);
+            VCpuExit::Halt
+        }
+        csr::Trap::Interrupt(_) => {
             panic!(
                 "Unknown trap: {:?}, stval: {:#x}, sepc: {:#x}, htval: {:#x}, htinst: {:#x}",
                 scause.cause(),
@@ -152,25 +436,28 @@ fn vmexit_handler(vcpu: &mut VCpu) -> bool {
             );
         }
     }
-    true
 }
 
 pub fn init_pcpus(boot_hart_id: usize, meta: &MachineMeta) {
-    assert_eq!(boot_hart_id, 0);
-    let cpu_nums: usize = meta.harts.len();
     let mut pcpus = Vec::new();
-    for cpu_id in 0..cpu_nums {
-        let stack_top = PHYS_FRAME_ALLOCATOR
+    for hart in meta.harts.iter() {
+        let stack_base = PHYS_FRAME_ALLOCATOR
             .lock()
             .alloc_frames(
                 (PCPU_STACK_SIZE + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K,
                 PAGE_SIZE_4K,
             )
             .expect("Failed to alloc pcpu stack");
+        let trap_stack_base = PHYS_FRAME_ALLOCATOR
+            .lock()
+            .alloc_frames(TRAP_STACK_SIZE / PAGE_SIZE_4K, PAGE_SIZE_4K)
+            .expect("Failed to alloc pcpu trap stack");
         let pcpu = PCpu {
-            hart_id: cpu_id,
-            stack_top,
-            vcpus: Mutex::new(Vec::new()),
+            hart_id: hart.hartid,
+            stack_top: stack_base + align_up(PCPU_STACK_SIZE, PAGE_SIZE_4K),
+            trap_stack_top: trap_stack_base + TRAP_STACK_SIZE,
+            time_slice: (meta.timebase_frequency / 1000 * SCHED_TIME_SLICE_MS) as u64,
+            run_queue: Mutex::new(RunQueue::new()),
         };
         info!("[Hypervisor] init pcpu: {:?}", pcpu);
         pcpus.push(pcpu);
@@ -182,11 +469,49 @@ pub fn init_pcpus(boot_hart_id: usize, meta: &MachineMeta) {
     info!("[Hypervisor] this cpu: {:?}", this_cpu());
 }
 
+/// Starts every hart other than the boot hart at `start_addr` through SBI HSM,
+/// passing the stack top of its pcpu as the opaque argument.
+pub fn start_secondary_cpus(boot_hart_id: usize, start_addr: usize) {
+    let pcpus = unsafe { GLOBAL_PCPUS.get_unchecked() };
+    RUNNING_PCPUS.store(pcpus.len(), Ordering::SeqCst);
+    for pcpu in pcpus.iter().filter(|pcpu| pcpu.hart_id != boot_hart_id) {
+        info!("[Hypervisor] start secondary hart {}", pcpu.hart_id);
+        let ret = sbi_rt::hart_start(pcpu.hart_id, start_addr, pcpu.stack_top.as_usize());
+        if ret.is_err() {
+            panic!(
+                "[Hypervisor] failed to start hart {}, err: {:?}",
+                pcpu.hart_id,
+                ret.err()
+            );
+        }
+    }
+}
+
+/// Called by a pcpu once all of its vcpus have halted. The last pcpu to finish
+/// powers off the machine, the others stop their hart.
+pub fn exit_this_cpu() -> ! {
+    let hart_id = this_cpu().hart_id;
+    if RUNNING_PCPUS.fetch_sub(1, Ordering::SeqCst) == 1 {
+        info!("[HyperVisor] exited");
+        sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    } else {
+        info!("[HyperVisor] hart {} stopped", hart_id);
+        sbi_rt::hart_stop();
+    }
+    unreachable!()
+}
+
 pub fn setup_this_cpu(hart_id: usize) {
+    // tp holds the index of this hart's pcpu in `GLOBAL_PCPUS`
+    let pcpu_id = unsafe { GLOBAL_PCPUS.get_unchecked() }
+        .iter()
+        .position(|pcpu| pcpu.hart_id == hart_id)
+        .expect("hart is not described in device tree");
     unsafe {
         // Safe since we're the only users of TP.
-        core::arch::asm!("mv tp, {rs}", rs = in(reg) hart_id)
+        core::arch::asm!("mv tp, {rs}", rs = in(reg) pcpu_id)
     };
+    trap::set_hypervisor_trap_entry(this_cpu().trap_stack_top.as_usize());
 }
 
 /// Returns this CPU's `PCpu` structure.
@@ -195,8 +520,3 @@ pub fn this_cpu() -> &'static PCpu {
     unsafe { core::arch::asm!("mv {rd}, tp", rd = out(reg) tp) };
     unsafe { GLOBAL_PCPUS.get_unchecked().get_unchecked(tp as usize) }
 }
-
-pub fn run_vcpus() -> ! {
-    let pcpu = this_cpu();
-    loop {}
-}
@@ -0,0 +1,168 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use log::{debug, info, warn};
+use spin::Once;
+
+use crate::dtb::MachineMeta;
+use crate::pcpu;
+use crate::vm::GLOBAL_VMS;
+
+const PRIORITY_BASE: usize = 0x0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// The physical PLIC, owned by the hypervisor. Host interrupt sources are claimed
+/// by the hypervisor and forwarded to the virtual PLIC of the vm owning them.
+pub struct Plic {
+    base: usize,
+    /// S-mode context of every hart, `(hart_id, context)`.
+    contexts: Vec<(usize, usize)>,
+    /// Source -> id of the vm it is routed to.
+    owners: BTreeMap<usize, usize>,
+}
+
+pub static HOST_PLIC: Once<Plic> = Once::new();
+
+impl Plic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn set_priority(&self, source: usize, priority: u32) {
+        unsafe {
+            self.reg(PRIORITY_BASE + source * 4)
+                .write_volatile(priority)
+        };
+    }
+
+    fn set_enable(&self, context: usize, source: usize, enable: bool) {
+        let reg = self.reg(ENABLE_BASE + context * ENABLE_STRIDE + source / 32 * 4);
+        unsafe {
+            let bits = reg.read_volatile();
+            let bits = if enable {
+                bits | 1 << (source % 32)
+            } else {
+                bits & !(1 << (source % 32))
+            };
+            reg.write_volatile(bits);
+        }
+    }
+
+    fn set_threshold(&self, context: usize, threshold: u32) {
+        let offset = CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_THRESHOLD;
+        unsafe { self.reg(offset).write_volatile(threshold) };
+    }
+
+    /// Claims the highest priority pending source for `context`, 0 if none.
+    fn claim(&self, context: usize) -> usize {
+        let offset = CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM;
+        unsafe { self.reg(offset).read_volatile() as usize }
+    }
+
+    fn complete(&self, context: usize, source: usize) {
+        let offset = CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM;
+        unsafe { self.reg(offset).write_volatile(source as u32) };
+    }
+
+    /// Context of the current hart.
+    fn this_context(&self) -> usize {
+        let hart_id = pcpu::this_cpu().hart_id;
+        self.contexts
+            .iter()
+            .find(|(hart, _)| *hart == hart_id)
+            .map(|(_, context)| *context)
+            .expect("no PLIC context for this hart")
+    }
+}
+
+/// Routes the host interrupt sources configured for each vm to it and enables
+/// them on every hart, must be called after the vms are created.
+pub fn init_host_plic(meta: &MachineMeta) {
+    let Some(dev) = meta.plic.as_ref() else {
+        return;
+    };
+    let mut owners = BTreeMap::new();
+    for vm in unsafe { GLOBAL_VMS.get_unchecked() } {
+        for source in vm.passthrough_irqs.iter() {
+            assert!(
+                *source != 0 && *source <= meta.plic_num_sources,
+                "no irq {} on the PLIC",
+                source
+            );
+            if let Some(owner) = owners.insert(*source, vm.vm_id) {
+                panic!(
+                    "irq {} is routed to both vm {} and vm {}",
+                    source, owner, vm.vm_id
+                );
+            }
+        }
+    }
+    let plic = Plic {
+        base: dev.base_address,
+        contexts: meta
+            .harts
+            .iter()
+            .map(|hart| (hart.hartid, hart.plic_context))
+            .collect(),
+        owners,
+    };
+    for (_, context) in plic.contexts.iter() {
+        plic.set_threshold(*context, 0);
+        for source in plic.owners.keys() {
+            plic.set_enable(*context, *source, true);
+        }
+    }
+    for (source, vm_id) in plic.owners.iter() {
+        plic.set_priority(*source, 1);
+        info!("[Hypervisor] route irq {} to vm {}", source, vm_id);
+    }
+    HOST_PLIC.call_once(|| plic);
+}
+
+/// Claims the pending host interrupts and makes them pending in the virtual PLIC
+/// of their vm. They are completed once the guest completes them.
+pub fn handle_external_interrupt() {
+    let Some(plic) = HOST_PLIC.get() else {
+        return;
+    };
+    let context = plic.this_context();
+    loop {
+        let source = plic.claim(context);
+        if source == 0 {
+            break;
+        }
+        debug!("[Hypervisor] host irq {}", source);
+        let vm = plic
+            .owners
+            .get(&source)
+            .map(|vm_id| unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(*vm_id) });
+        match vm.and_then(|vm| vm.vplic.as_ref()) {
+            Some(vplic) => vplic.raise(source),
+            None => {
+                warn!("[Hypervisor] unexpected host irq {}", source);
+                plic.complete(context, source);
+            }
+        }
+    }
+}
+
+/// Completes a host interrupt forwarded to a guest.
+pub fn complete_host_irq(source: usize) {
+    if let Some(plic) = HOST_PLIC.get() {
+        plic.complete(plic.this_context(), source);
+    }
+}
+
+/// Stops delivering a host interrupt source, e.g. when the vm owning it is shut down.
+pub fn disable_host_irq(source: usize) {
+    if let Some(plic) = HOST_PLIC.get() {
+        plic.set_priority(source, 0);
+        for (_, context) in plic.contexts.iter() {
+            plic.set_enable(*context, source, false);
+        }
+    }
+}
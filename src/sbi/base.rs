@@ -0,0 +1,46 @@
+use sbi_spec::base::UNAVAILABLE_EXTENSION;
+use sbi_spec::binary::SbiRet;
+
+use crate::pcpu::VCpuExit;
+use crate::vm::{VCpu, VM};
+
+use super::{extension_implemented, set_sbi_ret, unsupported_call};
+
+/// SBI implementation ID reported to guests. This hypervisor has no ID assigned
+/// by the SBI specification, so one far above the registered ones is used.
+pub const IMPL_ID: usize = 0x5256_4d00;
+
+/// SBI specification version emulated for guests unless configured otherwise, 2.0.
+pub const DEFAULT_SPEC_VERSION: usize = 2 << 24;
+
+pub fn handle_base(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    let a6 = vcpu.guest_cpu_state.gprs[16];
+
+    let ret = match a6 {
+        sbi_spec::base::GET_SBI_SPEC_VERSION => SbiRet::success(vm.sbi_spec_version),
+        sbi_spec::base::GET_SBI_IMPL_ID => SbiRet::success(IMPL_ID),
+        sbi_spec::base::GET_SBI_IMPL_VERSION => SbiRet::success(impl_version()),
+        sbi_spec::base::PROBE_EXTENSION => {
+            if extension_implemented(a0) {
+                SbiRet::success(1)
+            } else {
+                SbiRet::success(UNAVAILABLE_EXTENSION)
+            }
+        }
+        // guests see no real machine identity, 0 means "not implemented"
+        sbi_spec::base::GET_MVENDORID
+        | sbi_spec::base::GET_MARCHID
+        | sbi_spec::base::GET_MIMPID => SbiRet::success(0),
+        _ => return unsupported_call(vm, vcpu),
+    };
+    set_sbi_ret(vcpu, ret);
+    VCpuExit::Resume
+}
+
+/// The crate version, encoded as `major << 16 | minor`.
+fn impl_version() -> usize {
+    let major: usize = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+    let minor: usize = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+    major << 16 | minor
+}
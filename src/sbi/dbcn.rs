@@ -0,0 +1,93 @@
+use sbi_spec::binary::SbiRet;
+
+use crate::config::PAGE_SIZE_4K;
+use crate::mem::GuestPhysAddr;
+use crate::pcpu::VCpuExit;
+use crate::vm::{VCpu, VM};
+
+use super::{set_sbi_ret, unsupported_call};
+
+pub fn handle_dbcn(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    let a1 = vcpu.guest_cpu_state.gprs[11];
+    let a2 = vcpu.guest_cpu_state.gprs[12];
+    let a6 = vcpu.guest_cpu_state.gprs[16];
+
+    let ret = match a6 {
+        sbi_spec::dbcn::CONSOLE_WRITE => console_write(vm, a0, a1, a2),
+        sbi_spec::dbcn::CONSOLE_READ => console_read(vm, a0, a1, a2),
+        sbi_spec::dbcn::CONSOLE_WRITE_BYTE => {
+            vm.console_write(&[a0 as u8]);
+            SbiRet::success(0)
+        }
+        _ => return unsupported_call(vm, vcpu),
+    };
+    set_sbi_ret(vcpu, ret);
+    VCpuExit::Resume
+}
+
+fn console_write(vm: &VM, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    if !in_guest_ram(vm, num_bytes, base_lo, base_hi) {
+        return SbiRet::invalid_param();
+    }
+    let mut written = 0;
+    while written < num_bytes {
+        let Some(chunk) = guest_chunk(vm, base_lo + written, num_bytes - written) else {
+            break;
+        };
+        vm.console_write(chunk);
+        written += chunk.len();
+    }
+    partial_ret(written, num_bytes)
+}
+
+fn console_read(vm: &VM, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    if !in_guest_ram(vm, num_bytes, base_lo, base_hi) {
+        return SbiRet::invalid_param();
+    }
+    let mut read = 0;
+    while read < num_bytes {
+        let Some(chunk) = guest_chunk(vm, base_lo + read, num_bytes - read) else {
+            break;
+        };
+        let len = vm.console_read(chunk);
+        read += len;
+        if len < chunk.len() {
+            // no more input
+            return SbiRet::success(read);
+        }
+    }
+    partial_ret(read, num_bytes)
+}
+
+/// The bytes done, or an error if the guest buffer could not be accessed at all.
+fn partial_ret(done: usize, num_bytes: usize) -> SbiRet {
+    if done == 0 && num_bytes != 0 {
+        SbiRet::invalid_param()
+    } else {
+        SbiRet::success(done)
+    }
+}
+
+/// Whether a guest buffer lies in guest RAM.
+fn in_guest_ram(vm: &VM, num_bytes: usize, base_lo: usize, base_hi: usize) -> bool {
+    let ram_start = vm.memory_base.as_usize();
+    let ram_end = ram_start + vm.memory_limit;
+    base_hi == 0
+        && base_lo >= ram_start
+        && base_lo
+            .checked_add(num_bytes)
+            .is_some_and(|end| end <= ram_end)
+}
+
+/// The host memory backing the guest buffer at `gpa`, at most `len` bytes up to
+/// the end of its page since guest RAM is only contiguous within a page on the host.
+fn guest_chunk(vm: &VM, gpa: usize, len: usize) -> Option<&'static mut [u8]> {
+    let len = (PAGE_SIZE_4K - gpa % PAGE_SIZE_4K).min(len);
+    let hpa = vm
+        .guest_page_table
+        .lock()
+        .translate(GuestPhysAddr::from(gpa))
+        .ok()?;
+    Some(unsafe { core::slice::from_raw_parts_mut(hpa.as_usize() as *mut u8, len) })
+}
@@ -0,0 +1,82 @@
+use log::debug;
+use sbi_spec::binary::SbiRet;
+use sbi_spec::hsm::{hart_state, suspend_type};
+
+use crate::pcpu::VCpuExit;
+use crate::sched;
+use crate::vm::{VCpu, VM};
+
+use super::{set_sbi_ret, unsupported_call};
+
+pub fn handle_hsm(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    let a1 = vcpu.guest_cpu_state.gprs[11];
+    let a2 = vcpu.guest_cpu_state.gprs[12];
+    let a6 = vcpu.guest_cpu_state.gprs[16];
+
+    match a6 {
+        sbi_spec::hsm::HART_START => {
+            let ret = hart_start(vm, a0, a1, a2);
+            set_sbi_ret(vcpu, ret);
+            VCpuExit::Resume
+        }
+        sbi_spec::hsm::HART_STOP => {
+            debug!("[Hypervisor] vm {} vcpu {} stopped", vm.vm_id, vcpu.vcpu_id);
+            vm.vcpu_ctrls[vcpu.vcpu_id].set_hart_state(hart_state::STOPPED);
+            VCpuExit::Yield
+        }
+        sbi_spec::hsm::HART_GET_STATUS => {
+            let ret = match vm.vcpu_ctrls.get(a0) {
+                Some(ctrl) => SbiRet::success(ctrl.hart_state()),
+                None => SbiRet::invalid_param(),
+            };
+            set_sbi_ret(vcpu, ret);
+            VCpuExit::Resume
+        }
+        sbi_spec::hsm::HART_SUSPEND => hart_suspend(vm, vcpu, a0 as u32, a1, a2),
+        _ => unsupported_call(vm, vcpu),
+    }
+}
+
+fn hart_start(vm: &VM, hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    let Some(ctrl) = vm.vcpu_ctrls.get(hartid) else {
+        return SbiRet::invalid_param();
+    };
+    if !ctrl.transit_hart_state(hart_state::STOPPED, hart_state::START_PENDING) {
+        return SbiRet::already_available();
+    }
+    // a stopped vcpu is not running on any pcpu, so its lock is free
+    vm.vcpus[hartid].lock().reset(start_addr, opaque);
+    ctrl.set_timer_deadline(u64::MAX);
+    ctrl.set_hart_state(hart_state::STARTED);
+    sched::enqueue_vcpu((vm.vm_id, hartid));
+    debug!(
+        "[Hypervisor] vm {} vcpu {} started at {:#x}",
+        vm.vm_id, hartid, start_addr
+    );
+    SbiRet::success(0)
+}
+
+fn hart_suspend(
+    vm: &VM,
+    vcpu: &mut VCpu,
+    suspend_type: u32,
+    resume_addr: usize,
+    opaque: usize,
+) -> VCpuExit {
+    match suspend_type {
+        suspend_type::RETENTIVE => {
+            // resumes right after the ecall, as if the call returned successfully
+            set_sbi_ret(vcpu, SbiRet::success(0));
+        }
+        suspend_type::NON_RETENTIVE => {
+            vcpu.reset(resume_addr, opaque);
+        }
+        _ => {
+            set_sbi_ret(vcpu, SbiRet::invalid_param());
+            return VCpuExit::Resume;
+        }
+    }
+    vm.vcpu_ctrls[vcpu.vcpu_id].set_hart_state(hart_state::SUSPENDED);
+    VCpuExit::Yield
+}
@@ -0,0 +1,38 @@
+use sbi_spec::binary::SbiRet;
+
+use crate::pcpu::VCpuExit;
+use crate::sched;
+use crate::vm::{VCpu, VM};
+
+use super::{hart_mask_to_vcpus, set_sbi_ret, unsupported_call};
+
+pub fn handle_ipi(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    let a1 = vcpu.guest_cpu_state.gprs[11];
+    let a6 = vcpu.guest_cpu_state.gprs[16];
+
+    match a6 {
+        sbi_spec::spi::SEND_IPI => {
+            let ret = send_ipi(vm, vcpu.vcpu_id, a0, a1);
+            set_sbi_ret(vcpu, ret);
+            VCpuExit::Resume
+        }
+        _ => unsupported_call(vm, vcpu),
+    }
+}
+
+/// Makes a supervisor software interrupt pending on the vcpus in the hart mask,
+/// where guest hart IDs are vcpu IDs.
+fn send_ipi(vm: &VM, caller: usize, hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    let Some(targets) = hart_mask_to_vcpus(vm, hart_mask, hart_mask_base) else {
+        return SbiRet::invalid_param();
+    };
+    for vcpu_id in targets {
+        vm.vcpu_ctrls[vcpu_id].set_ipi_pending();
+        // the caller picks its own interrupt up when it re-enters the guest
+        if vcpu_id != caller {
+            sched::wake_vcpu((vm.vm_id, vcpu_id));
+        }
+    }
+    SbiRet::success(0)
+}
@@ -1,61 +1,186 @@
-use log::debug;
+mod base;
+mod dbcn;
+mod hsm;
+mod ipi;
+mod rfence;
 
-use crate::{csr, vm::VCpu};
+pub use base::DEFAULT_SPEC_VERSION;
+pub use rfence::flush_requested;
 
-pub fn handle_sbi_call(vcpu: &mut VCpu) {
+use log::{debug, warn};
+use sbi_spec::binary::SbiRet;
+
+use crate::{
+    csr,
+    pcpu::VCpuExit,
+    vm::{VCpu, VM},
+};
+
+/// Handles an ecall from VS-mode. `sepc` has already been moved past the ecall.
+pub fn handle_sbi_call(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
     let a7 = vcpu.guest_cpu_state.gprs[17];
     match a7 {
-        sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR => handle_console_putchar(vcpu),
-        sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR => handle_console_getchar(vcpu),
-        sbi_spec::legacy::LEGACY_SHUTDOWN => handle_shutdown(vcpu),
-        sbi_spec::srst::EID_SRST => handle_reset(vcpu),
-        sbi_spec::time::EID_TIME => handle_time(vcpu),
-        _ => panic!("[Hypervisor] Unsupported SBI call!"),
+        sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR => handle_console_putchar(vm, vcpu),
+        sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR => handle_console_getchar(vm, vcpu),
+        sbi_spec::legacy::LEGACY_SHUTDOWN => {
+            vm.shutdown(vcpu.vcpu_id);
+            return VCpuExit::Halt;
+        }
+        sbi_spec::srst::EID_SRST => return handle_reset(vm, vcpu),
+        sbi_spec::base::EID_BASE => return base::handle_base(vm, vcpu),
+        sbi_spec::time::EID_TIME => return handle_time(vm, vcpu),
+        sbi_spec::hsm::EID_HSM => return hsm::handle_hsm(vm, vcpu),
+        sbi_spec::spi::EID_SPI => return ipi::handle_ipi(vm, vcpu),
+        sbi_spec::rfnc::EID_RFNC => return rfence::handle_rfence(vm, vcpu),
+        sbi_spec::dbcn::EID_DBCN => return dbcn::handle_dbcn(vm, vcpu),
+        _ => return unsupported_call(vm, vcpu),
     }
+    VCpuExit::Resume
 }
 
-fn handle_console_putchar(vcpu: &mut VCpu) {
-    let a0 = vcpu.guest_cpu_state.gprs[10];
-    let ret = sbi_rt::legacy::console_putchar(a0);
-    vcpu.guest_cpu_state.gprs[10] = ret;
+/// Fails an SBI call which is not implemented with `SBI_ERR_NOT_SUPPORTED`, or
+/// terminates the calling vm if it is in strict mode.
+fn unsupported_call(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let eid = vcpu.guest_cpu_state.gprs[17];
+    let fid = vcpu.guest_cpu_state.gprs[16];
+    warn!(
+        "[Hypervisor] vm {} ({}) vcpu {}: unsupported SBI call, eid: {:#x}, fid: {:#x}",
+        vm.vm_id, vm.name, vcpu.vcpu_id, eid, fid
+    );
+    if vm.sbi_strict {
+        vm.shutdown(vcpu.vcpu_id);
+        return VCpuExit::Halt;
+    }
+    if eid <= LEGACY_EID_MAX {
+        // legacy calls only return an error code and preserve a1
+        vcpu.guest_cpu_state.gprs[10] = SbiRet::not_supported().error;
+    } else {
+        set_sbi_ret(vcpu, SbiRet::not_supported());
+    }
+    VCpuExit::Resume
 }
 
-fn handle_console_getchar(vcpu: &mut VCpu) {
-    let ret = sbi_rt::legacy::console_getchar();
-    vcpu.guest_cpu_state.gprs[10] = ret;
+/// Extension IDs up to this one belong to the legacy extensions.
+const LEGACY_EID_MAX: usize = 0x0f;
+
+/// Whether `handle_sbi_call` emulates the extension `eid`, as reported by
+/// `probe_extension`.
+fn extension_implemented(eid: usize) -> bool {
+    matches!(
+        eid,
+        sbi_spec::legacy::LEGACY_CONSOLE_PUTCHAR
+            | sbi_spec::legacy::LEGACY_CONSOLE_GETCHAR
+            | sbi_spec::legacy::LEGACY_SHUTDOWN
+            | sbi_spec::base::EID_BASE
+            | sbi_spec::srst::EID_SRST
+            | sbi_spec::time::EID_TIME
+            | sbi_spec::hsm::EID_HSM
+            | sbi_spec::spi::EID_SPI
+            | sbi_spec::rfnc::EID_RFNC
+            | sbi_spec::dbcn::EID_DBCN
+    )
 }
 
-fn handle_shutdown(vcpu: &mut VCpu) {
-    // TODO
-    debug!("[Hypervisor] Shutdown vm!");
-    vcpu.guest_cpu_state.gprs[10] = 0;
+/// Returns the vcpus selected by an SBI hart mask, or `None` if it names a hart
+/// which does not exist. A `hart_mask_base` of `usize::MAX` selects every hart.
+fn hart_mask_to_vcpus(
+    vm: &VM,
+    hart_mask: usize,
+    hart_mask_base: usize,
+) -> Option<impl Iterator<Item = usize>> {
+    let num_vcpus = vm.vcpus.len();
+    let (mask, base) = if hart_mask_base == usize::MAX {
+        (usize::MAX, 0)
+    } else {
+        if hart_mask_base >= num_vcpus {
+            return None;
+        }
+        if hart_mask != 0 {
+            let max_hart = usize::BITS as usize - 1 - hart_mask.leading_zeros() as usize;
+            if hart_mask_base + max_hart >= num_vcpus {
+                return None;
+            }
+        }
+        (hart_mask, hart_mask_base)
+    };
+    Some(
+        (0..usize::BITS as usize)
+            .filter(move |bit| mask & (1 << bit) != 0)
+            .map(move |bit| base + bit)
+            .take_while(move |vcpu_id| *vcpu_id < num_vcpus),
+    )
+}
+
+fn set_sbi_ret(vcpu: &mut VCpu, ret: SbiRet) {
+    vcpu.guest_cpu_state.gprs[10] = ret.error;
+    vcpu.guest_cpu_state.gprs[11] = ret.value;
 }
 
-fn handle_reset(vcpu: &mut VCpu) {
-    // TODO
-    // debug!("[Hypervisor] Reset vm!");
+fn handle_console_putchar(vm: &VM, vcpu: &mut VCpu) {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    vm.console_write(&[a0 as u8]);
     vcpu.guest_cpu_state.gprs[10] = 0;
 }
 
-fn handle_time(vcpu: &mut VCpu) {
+fn handle_console_getchar(vm: &VM, vcpu: &mut VCpu) {
+    let mut c = [0];
+    let ret = match vm.console_read(&mut c) {
+        0 => usize::MAX,
+        _ => c[0] as usize,
+    };
+    vcpu.guest_cpu_state.gprs[10] = ret;
+}
+
+/// Shuts down or reboots the calling vm only.
+fn handle_reset(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    let a1 = vcpu.guest_cpu_state.gprs[11];
+    let a6 = vcpu.guest_cpu_state.gprs[16];
+    if a6 != sbi_spec::srst::SYSTEM_RESET {
+        return unsupported_call(vm, vcpu);
+    }
+    debug!(
+        "[Hypervisor] vm {} system reset, type: {:#x}, reason: {:#x}",
+        vm.vm_id, a0, a1
+    );
+
+    let reboot = match a0 as u32 {
+        sbi_spec::srst::RESET_TYPE_SHUTDOWN => {
+            vm.shutdown(vcpu.vcpu_id);
+            return VCpuExit::Halt;
+        }
+        sbi_spec::srst::RESET_TYPE_COLD_REBOOT => vm.reboot(vcpu, true),
+        sbi_spec::srst::RESET_TYPE_WARM_REBOOT => vm.reboot(vcpu, false),
+        _ => {
+            set_sbi_ret(vcpu, SbiRet::invalid_param());
+            return VCpuExit::Resume;
+        }
+    };
+    reboot.unwrap_or_else(|err| {
+        warn!("[Hypervisor] failed to reboot vm {}: {:?}", vm.vm_id, err);
+        vm.shutdown(vcpu.vcpu_id);
+        VCpuExit::Halt
+    })
+}
+
+fn handle_time(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
     debug!("[Hypervisor] Time!");
     let a0 = vcpu.guest_cpu_state.gprs[10];
     let a6 = vcpu.guest_cpu_state.gprs[16];
 
     match a6 {
         sbi_spec::time::SET_TIMER => {
-            let ret = sbi_rt::set_timer(a0 as u64);
-
-            unsafe {
-                riscv::register::sie::set_stimer();
+            if csr::sstc_supported() {
+                // the hardware raises the guest timer interrupt by itself
+                csr::vstimecmp::write(a0);
             }
-            let mut hvip = csr::Hvip::read();
-            hvip.set_vs_timer_interrupt(false);
-            hvip.write();
-
-            vcpu.guest_cpu_state.gprs[10] = ret.error;
-            vcpu.guest_cpu_state.gprs[11] = ret.value;
+            // without Sstc the scheduler multiplexes this deadline onto the physical
+            // timer and keeps the guest timer interrupt pending until it passes
+            let deadline = vcpu.host_time(a0 as u64);
+            vm.vcpu_ctrls[vcpu.vcpu_id].set_timer_deadline(deadline);
+            set_sbi_ret(vcpu, SbiRet::success(0));
+            VCpuExit::Resume
         }
-        _ => panic!("[Hypervisor] Unsupported TIME SBI call!"),
+        _ => unsupported_call(vm, vcpu),
     }
 }
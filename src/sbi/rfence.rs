@@ -0,0 +1,133 @@
+use alloc::vec::Vec;
+use sbi_spec::binary::SbiRet;
+
+use crate::config::PAGE_SIZE_4K;
+use crate::pcpu::{self, VCpuExit};
+use crate::sched;
+use crate::vm::{VCpu, VCpuControl, FENCE_I, HFENCE_GVMA, SFENCE_VMA, VM};
+
+use super::{hart_mask_to_vcpus, set_sbi_ret, unsupported_call};
+
+/// Ranges larger than this are flushed as a whole rather than page by page.
+const MAX_FLUSH_PAGES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+enum Fence {
+    I,
+    Vma {
+        start: usize,
+        size: usize,
+        asid: Option<usize>,
+    },
+}
+
+pub fn handle_rfence(vm: &VM, vcpu: &mut VCpu) -> VCpuExit {
+    let a0 = vcpu.guest_cpu_state.gprs[10];
+    let a1 = vcpu.guest_cpu_state.gprs[11];
+    let a2 = vcpu.guest_cpu_state.gprs[12];
+    let a3 = vcpu.guest_cpu_state.gprs[13];
+    let a4 = vcpu.guest_cpu_state.gprs[14];
+    let a6 = vcpu.guest_cpu_state.gprs[16];
+
+    let fence = match a6 {
+        sbi_spec::rfnc::REMOTE_FENCE_I => Fence::I,
+        sbi_spec::rfnc::REMOTE_SFENCE_VMA => Fence::Vma {
+            start: a2,
+            size: a3,
+            asid: None,
+        },
+        sbi_spec::rfnc::REMOTE_SFENCE_VMA_ASID => Fence::Vma {
+            start: a2,
+            size: a3,
+            asid: Some(a4),
+        },
+        // guests do not see the hypervisor extension
+        _ => return unsupported_call(vm, vcpu),
+    };
+    let ret = remote_fence(vm, vcpu.vcpu_id, a0, a1, fence);
+    set_sbi_ret(vcpu, ret);
+    VCpuExit::Resume
+}
+
+/// Runs `fence` for the vcpus in the hart mask.
+///
+/// The caller is flushed right away, since its VMID is loaded on this pcpu. The
+/// other vcpus flush all their translations before they next enter the guest,
+/// and those currently running are kicked and waited for.
+fn remote_fence(
+    vm: &VM,
+    caller: usize,
+    hart_mask: usize,
+    hart_mask_base: usize,
+    fence: Fence,
+) -> SbiRet {
+    let Some(targets) = hart_mask_to_vcpus(vm, hart_mask, hart_mask_base) else {
+        return SbiRet::invalid_param();
+    };
+    let request = match fence {
+        Fence::I => FENCE_I,
+        Fence::Vma { .. } => SFENCE_VMA,
+    };
+    let mut running = Vec::new();
+    for vcpu_id in targets {
+        if vcpu_id == caller {
+            local_fence(fence);
+            continue;
+        }
+        let ctrl = &vm.vcpu_ctrls[vcpu_id];
+        ctrl.request_fence(request);
+        if ctrl.is_running() {
+            sched::kick_vcpu((vm.vm_id, vcpu_id));
+            running.push(vcpu_id);
+        }
+    }
+
+    let caller_ctrl = &vm.vcpu_ctrls[caller];
+    let in_flight = |vcpu_id: &usize| {
+        let ctrl = &vm.vcpu_ctrls[*vcpu_id];
+        ctrl.fence_pending() && ctrl.is_running()
+    };
+    while running.iter().any(in_flight) {
+        // another vcpu may be waiting for the caller in the same way
+        flush_requested(caller_ctrl);
+        core::hint::spin_loop();
+    }
+    SbiRet::success(0)
+}
+
+fn local_fence(fence: Fence) {
+    match fence {
+        Fence::I => unsafe { core::arch::asm!("fence.i") },
+        Fence::Vma { start, size, asid } => {
+            // `start == 0 && size == 0` and `size == usize::MAX` mean everything
+            let flush_all = (start == 0 && size == 0) || size == usize::MAX;
+            if size == 0 && !flush_all {
+                return;
+            }
+            if flush_all || size / PAGE_SIZE_4K > MAX_FLUSH_PAGES {
+                pcpu::hfence_vvma(None, asid);
+                return;
+            }
+            let mut addr = start & !(PAGE_SIZE_4K - 1);
+            while addr < start.saturating_add(size) {
+                pcpu::hfence_vvma(Some(addr), asid);
+                addr += PAGE_SIZE_4K;
+            }
+        }
+    }
+}
+
+/// Executes the fences requested for the vcpu owning `ctrl`, which must be
+/// loaded on this pcpu.
+pub fn flush_requested(ctrl: &VCpuControl) {
+    let requests = ctrl.take_fence_requests();
+    if requests & FENCE_I != 0 {
+        local_fence(Fence::I);
+    }
+    if requests & SFENCE_VMA != 0 {
+        pcpu::hfence_vvma(None, None);
+    }
+    if requests & HFENCE_GVMA != 0 {
+        pcpu::hfence_gvma();
+    }
+}
@@ -0,0 +1,144 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use log::debug;
+use sbi_spec::hsm::hart_state;
+
+use crate::pcpu::{VCpuExit, GLOBAL_PCPUS};
+use crate::vm::GLOBAL_VMS;
+
+/// (vm_id, vcpu_id)
+pub type VCpuRef = (usize, usize);
+
+/// Round-robin run queue of the vcpus bound to one pcpu.
+///
+/// A bound vcpu is either running, ready, blocked waiting for an interrupt
+/// (guest `wfi` or HSM suspend), or stopped by HSM and waiting for `hart_start`.
+#[derive(Debug, Default)]
+pub struct RunQueue {
+    ready: VecDeque<VCpuRef>,
+    blocked: Vec<VCpuRef>,
+    /// Bound vcpus which have not halted.
+    alive: Vec<VCpuRef>,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            ready: VecDeque::new(),
+            blocked: Vec::new(),
+            alive: Vec::new(),
+        }
+    }
+
+    pub fn bind(&mut self, vcpu: VCpuRef) {
+        self.alive.push(vcpu);
+        if vcpu_hart_state(vcpu) == hart_state::STARTED {
+            self.ready.push_back(vcpu);
+        }
+    }
+
+    /// Whether every bound vcpu has halted.
+    pub fn is_finished(&self) -> bool {
+        self.alive.is_empty()
+    }
+
+    /// Picks the vcpu to run next, waking up blocked vcpus whose timer has expired.
+    pub fn pick_next(&mut self, now: u64) -> Option<VCpuRef> {
+        let mut i = 0;
+        while i < self.blocked.len() {
+            if vcpu_timer_deadline(self.blocked[i]) <= now {
+                let vcpu = self.blocked.swap_remove(i);
+                self.ready.push_back(vcpu);
+            } else {
+                i += 1;
+            }
+        }
+        // a halted vcpu may have been enqueued again while it was running
+        while let Some(vcpu) = self.ready.pop_front() {
+            if self.alive.contains(&vcpu) {
+                return Some(vcpu);
+            }
+        }
+        None
+    }
+
+    /// Puts back the vcpu which just ran according to why it stopped running.
+    pub fn put_prev(&mut self, vcpu: VCpuRef, exit: VCpuExit) {
+        match exit {
+            VCpuExit::Halt => {
+                self.alive.retain(|v| *v != vcpu);
+                self.ready.retain(|v| *v != vcpu);
+                self.blocked.retain(|v| *v != vcpu);
+            }
+            // it may have been woken up while it was running
+            _ if self.ready.contains(&vcpu) => {}
+            VCpuExit::Block => self.blocked.push(vcpu),
+            VCpuExit::Resume | VCpuExit::Yield => match vcpu_hart_state(vcpu) {
+                hart_state::STARTED => self.ready.push_back(vcpu),
+                hart_state::SUSPENDED => self.blocked.push(vcpu),
+                // stopped vcpus wait for `enqueue` from `hart_start`
+                _ => {}
+            },
+        }
+    }
+
+    /// Makes a stopped or blocked vcpu ready to run.
+    pub fn enqueue(&mut self, vcpu: VCpuRef) {
+        if !self.alive.contains(&vcpu) {
+            return;
+        }
+        self.blocked.retain(|v| *v != vcpu);
+        if !self.ready.contains(&vcpu) {
+            self.ready.push_back(vcpu);
+        }
+    }
+
+    /// The earliest timer deadline among blocked vcpus.
+    pub fn next_deadline(&self) -> u64 {
+        self.blocked
+            .iter()
+            .map(|vcpu| vcpu_timer_deadline(*vcpu))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+fn vcpu_hart_state((vm_id, vcpu_id): VCpuRef) -> usize {
+    let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(vm_id) };
+    vm.vcpu_ctrls[vcpu_id].hart_state()
+}
+
+fn vcpu_timer_deadline((vm_id, vcpu_id): VCpuRef) -> u64 {
+    let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(vm_id) };
+    vm.vcpu_ctrls[vcpu_id].timer_deadline()
+}
+
+/// Puts a vcpu on the run queue of its pcpu, e.g. after it was started by HSM.
+pub fn enqueue_vcpu(vcpu: VCpuRef) {
+    let pcpu_id = vcpu_pcpu_id(vcpu);
+    let pcpu = unsafe { GLOBAL_PCPUS.get_unchecked().get_unchecked(pcpu_id) };
+    pcpu.run_queue.lock().enqueue(vcpu);
+    debug!("[Hypervisor] enqueue vcpu {:?} on pcpu {}", vcpu, pcpu_id);
+    pcpu.kick();
+}
+
+/// Makes a vcpu waiting for an interrupt runnable again, and makes its pcpu
+/// re-enter it if it is running so that pending interrupts are injected.
+pub fn wake_vcpu(vcpu: VCpuRef) {
+    match vcpu_hart_state(vcpu) {
+        hart_state::STARTED | hart_state::SUSPENDED => enqueue_vcpu(vcpu),
+        _ => {}
+    }
+}
+
+/// Makes the pcpu of a running vcpu exit and re-enter it.
+pub fn kick_vcpu(vcpu: VCpuRef) {
+    let pcpu_id = vcpu_pcpu_id(vcpu);
+    let pcpu = unsafe { GLOBAL_PCPUS.get_unchecked().get_unchecked(pcpu_id) };
+    pcpu.kick();
+}
+
+fn vcpu_pcpu_id((vm_id, vcpu_id): VCpuRef) -> usize {
+    let vm = unsafe { GLOBAL_VMS.get_unchecked().get_unchecked(vm_id) };
+    vm.vcpu_ctrls[vcpu_id].pcpu_id()
+}
@@ -1,20 +1,28 @@
 use log::info;
 
-use crate::config::PAGE_SIZE_4K;
+use crate::config::TRAP_STACK_SIZE;
+use crate::csr;
+use crate::vm;
 
-// TODO this is a temporary solution
-static TRAP_STACK: [u8; 10 * PAGE_SIZE_4K] = [0u8; 10 * PAGE_SIZE_4K];
+/// Trap stack of the boot hart until its pcpu is set up.
+static BOOT_TRAP_STACK: [u8; TRAP_STACK_SIZE] = [0u8; TRAP_STACK_SIZE];
 
-pub fn set_hypervisor_trap_entry() {
+/// Sets the trap entry of this hart, traps are taken on the stack ending at
+/// `trap_stack_top` which must not be shared with other harts.
+pub fn set_hypervisor_trap_entry(trap_stack_top: usize) {
     unsafe {
         riscv::register::stvec::write(
             _trap_vector_base as usize,
             riscv::register::stvec::TrapMode::Direct,
         );
-        riscv::register::sscratch::write(TRAP_STACK.as_ptr() as usize + TRAP_STACK.len());
+        riscv::register::sscratch::write(trap_stack_top);
     }
 }
 
+pub fn boot_trap_stack_top() -> usize {
+    BOOT_TRAP_STACK.as_ptr() as usize + BOOT_TRAP_STACK.len()
+}
+
 #[naked]
 #[no_mangle]
 pub unsafe extern "C" fn _trap_vector_base() -> ! {
@@ -98,6 +106,7 @@ pub unsafe extern "C" fn _trap_vector_base() -> ! {
         "ld t6, 30*8(sp)",
 
         "addi sp, sp, {trapframe_size}",
+        "csrrw sp, sscratch, sp",
         "sret",
         trapframe_size = const core::mem::size_of::<TrapFrame>(),
     );
@@ -105,7 +114,19 @@ pub unsafe extern "C" fn _trap_vector_base() -> ! {
 
 #[no_mangle]
 pub fn trap_handler(tf: &mut TrapFrame) {
-    panic!("trap_handler");
+    let scause = csr::Scause::read();
+    if let csr::Trap::Exception(_) = scause.cause() {
+        // a guest memory access from the hypervisor hit an unmapped page
+        if vm::fixup_guest_load(tf) {
+            return;
+        }
+    }
+    panic!(
+        "trap_handler: {:?}, stval: {:#x}, sepc: {:#x}",
+        scause.cause(),
+        riscv::register::stval::read(),
+        tf.sepc
+    );
 }
 
 /// General registers of RISC-V.
@@ -0,0 +1,171 @@
+use core::mem::offset_of;
+
+/// Floating-point register file of a vcpu.
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct FpState {
+    pub fregs: [u64; 32],
+    pub fcsr: usize,
+}
+
+impl FpState {
+    pub const fn freg_offset(index: usize) -> usize {
+        assert!(index < 32);
+        offset_of!(FpState, fregs) + index * 8
+    }
+}
+
+/// Saves `f0-f31` and `fcsr`. `sstatus.FS` must not be Off.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _save_fp_state(state: &mut FpState) {
+    core::arch::naked_asm!(
+        "fsd  f0, ({f0})(a0)",
+        "fsd  f1, ({f1})(a0)",
+        "fsd  f2, ({f2})(a0)",
+        "fsd  f3, ({f3})(a0)",
+        "fsd  f4, ({f4})(a0)",
+        "fsd  f5, ({f5})(a0)",
+        "fsd  f6, ({f6})(a0)",
+        "fsd  f7, ({f7})(a0)",
+        "fsd  f8, ({f8})(a0)",
+        "fsd  f9, ({f9})(a0)",
+        "fsd  f10, ({f10})(a0)",
+        "fsd  f11, ({f11})(a0)",
+        "fsd  f12, ({f12})(a0)",
+        "fsd  f13, ({f13})(a0)",
+        "fsd  f14, ({f14})(a0)",
+        "fsd  f15, ({f15})(a0)",
+        "fsd  f16, ({f16})(a0)",
+        "fsd  f17, ({f17})(a0)",
+        "fsd  f18, ({f18})(a0)",
+        "fsd  f19, ({f19})(a0)",
+        "fsd  f20, ({f20})(a0)",
+        "fsd  f21, ({f21})(a0)",
+        "fsd  f22, ({f22})(a0)",
+        "fsd  f23, ({f23})(a0)",
+        "fsd  f24, ({f24})(a0)",
+        "fsd  f25, ({f25})(a0)",
+        "fsd  f26, ({f26})(a0)",
+        "fsd  f27, ({f27})(a0)",
+        "fsd  f28, ({f28})(a0)",
+        "fsd  f29, ({f29})(a0)",
+        "fsd  f30, ({f30})(a0)",
+        "fsd  f31, ({f31})(a0)",
+        "frcsr t0",
+        "sd   t0, ({fcsr})(a0)",
+        "ret",
+        f0 = const FpState::freg_offset(0),
+        f1 = const FpState::freg_offset(1),
+        f2 = const FpState::freg_offset(2),
+        f3 = const FpState::freg_offset(3),
+        f4 = const FpState::freg_offset(4),
+        f5 = const FpState::freg_offset(5),
+        f6 = const FpState::freg_offset(6),
+        f7 = const FpState::freg_offset(7),
+        f8 = const FpState::freg_offset(8),
+        f9 = const FpState::freg_offset(9),
+        f10 = const FpState::freg_offset(10),
+        f11 = const FpState::freg_offset(11),
+        f12 = const FpState::freg_offset(12),
+        f13 = const FpState::freg_offset(13),
+        f14 = const FpState::freg_offset(14),
+        f15 = const FpState::freg_offset(15),
+        f16 = const FpState::freg_offset(16),
+        f17 = const FpState::freg_offset(17),
+        f18 = const FpState::freg_offset(18),
+        f19 = const FpState::freg_offset(19),
+        f20 = const FpState::freg_offset(20),
+        f21 = const FpState::freg_offset(21),
+        f22 = const FpState::freg_offset(22),
+        f23 = const FpState::freg_offset(23),
+        f24 = const FpState::freg_offset(24),
+        f25 = const FpState::freg_offset(25),
+        f26 = const FpState::freg_offset(26),
+        f27 = const FpState::freg_offset(27),
+        f28 = const FpState::freg_offset(28),
+        f29 = const FpState::freg_offset(29),
+        f30 = const FpState::freg_offset(30),
+        f31 = const FpState::freg_offset(31),
+        fcsr = const offset_of!(FpState, fcsr),
+    );
+}
+
+/// Loads `f0-f31` and `fcsr`. `sstatus.FS` must not be Off.
+///
+/// The hypervisor itself never uses floating-point registers, so clobbering
+/// them here is fine.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _restore_fp_state(state: &FpState) {
+    core::arch::naked_asm!(
+        "fld  f0, ({f0})(a0)",
+        "fld  f1, ({f1})(a0)",
+        "fld  f2, ({f2})(a0)",
+        "fld  f3, ({f3})(a0)",
+        "fld  f4, ({f4})(a0)",
+        "fld  f5, ({f5})(a0)",
+        "fld  f6, ({f6})(a0)",
+        "fld  f7, ({f7})(a0)",
+        "fld  f8, ({f8})(a0)",
+        "fld  f9, ({f9})(a0)",
+        "fld  f10, ({f10})(a0)",
+        "fld  f11, ({f11})(a0)",
+        "fld  f12, ({f12})(a0)",
+        "fld  f13, ({f13})(a0)",
+        "fld  f14, ({f14})(a0)",
+        "fld  f15, ({f15})(a0)",
+        "fld  f16, ({f16})(a0)",
+        "fld  f17, ({f17})(a0)",
+        "fld  f18, ({f18})(a0)",
+        "fld  f19, ({f19})(a0)",
+        "fld  f20, ({f20})(a0)",
+        "fld  f21, ({f21})(a0)",
+        "fld  f22, ({f22})(a0)",
+        "fld  f23, ({f23})(a0)",
+        "fld  f24, ({f24})(a0)",
+        "fld  f25, ({f25})(a0)",
+        "fld  f26, ({f26})(a0)",
+        "fld  f27, ({f27})(a0)",
+        "fld  f28, ({f28})(a0)",
+        "fld  f29, ({f29})(a0)",
+        "fld  f30, ({f30})(a0)",
+        "fld  f31, ({f31})(a0)",
+        "ld   t0, ({fcsr})(a0)",
+        "fscsr t0",
+        "ret",
+        f0 = const FpState::freg_offset(0),
+        f1 = const FpState::freg_offset(1),
+        f2 = const FpState::freg_offset(2),
+        f3 = const FpState::freg_offset(3),
+        f4 = const FpState::freg_offset(4),
+        f5 = const FpState::freg_offset(5),
+        f6 = const FpState::freg_offset(6),
+        f7 = const FpState::freg_offset(7),
+        f8 = const FpState::freg_offset(8),
+        f9 = const FpState::freg_offset(9),
+        f10 = const FpState::freg_offset(10),
+        f11 = const FpState::freg_offset(11),
+        f12 = const FpState::freg_offset(12),
+        f13 = const FpState::freg_offset(13),
+        f14 = const FpState::freg_offset(14),
+        f15 = const FpState::freg_offset(15),
+        f16 = const FpState::freg_offset(16),
+        f17 = const FpState::freg_offset(17),
+        f18 = const FpState::freg_offset(18),
+        f19 = const FpState::freg_offset(19),
+        f20 = const FpState::freg_offset(20),
+        f21 = const FpState::freg_offset(21),
+        f22 = const FpState::freg_offset(22),
+        f23 = const FpState::freg_offset(23),
+        f24 = const FpState::freg_offset(24),
+        f25 = const FpState::freg_offset(25),
+        f26 = const FpState::freg_offset(26),
+        f27 = const FpState::freg_offset(27),
+        f28 = const FpState::freg_offset(28),
+        f29 = const FpState::freg_offset(29),
+        f30 = const FpState::freg_offset(30),
+        f31 = const FpState::freg_offset(31),
+        fcsr = const offset_of!(FpState, fcsr),
+    );
+}
@@ -0,0 +1,109 @@
+use core::arch::asm;
+
+use crate::csr;
+use crate::error::{HypervisorError, HypervisorResult};
+use crate::trap::TrapFrame;
+
+use super::{instruction_len, VCpu};
+
+/// Loads from guest virtual memory with a hypervisor virtual-machine load
+/// instruction, `.insn r 0x73, 4, funct7, rd, rs1, rs2`.
+///
+/// A fault on the access is fixed up by `fixup_guest_load`, which skips the
+/// instruction and sets `a1`.
+macro_rules! guest_load {
+    ($gva:expr, $funct7:literal, $rs2:literal) => {{
+        let value: usize;
+        let faulted: usize;
+        asm!(
+            concat!(".insn r 0x73, 0x4, ", $funct7, ", a0, a0, ", $rs2),
+            inout("a0") $gva => value,
+            inout("a1") 0usize => faulted,
+            options(nostack),
+        );
+        if faulted == 0 {
+            Ok(value)
+        } else {
+            Err(HypervisorError::GuestMemoryFault)
+        }
+    }};
+}
+
+/// Runs `f` with guest memory accesses made at the privilege the vcpu trapped from.
+///
+/// The guest's `vsatp` and `hgatp` must be live.
+fn with_guest_privilege<T>(vcpu: &VCpu, f: impl FnOnce() -> T) -> T {
+    let hstatus = csr::Hstatus::read();
+    let mut guest = hstatus;
+    guest.set_spvp(csr::Hstatus::from_bits(vcpu.guest_cpu_state.hstatus).spvp());
+    guest.write();
+    let ret = f();
+    hstatus.write();
+    ret
+}
+
+/// A value `read_guest` loads with a `hlv` instruction of its width.
+pub trait GuestLoad: Sized {
+    /// # Safety
+    ///
+    /// The guest's `vsatp` and `hgatp` must be live.
+    unsafe fn guest_load(gva: usize) -> HypervisorResult<Self>;
+}
+
+macro_rules! impl_guest_load {
+    ($($ty:ty => $funct7:literal, $rs2:literal;)*) => {$(
+        impl GuestLoad for $ty {
+            unsafe fn guest_load(gva: usize) -> HypervisorResult<Self> {
+                guest_load!(gva, $funct7, $rs2).map(|value: usize| value as $ty)
+            }
+        }
+    )*};
+}
+
+impl_guest_load! {
+    // hlv.bu
+    u8 => "0x30", "x1";
+    // hlv.hu
+    u16 => "0x32", "x1";
+    // hlv.wu
+    u32 => "0x34", "x1";
+    // hlv.d
+    u64 => "0x36", "x0";
+}
+
+/// Reads a `T` from guest virtual memory, checking read permission.
+pub fn read_guest<T: GuestLoad>(vcpu: &VCpu, gva: usize) -> HypervisorResult<T> {
+    with_guest_privilege(vcpu, || unsafe { T::guest_load(gva) })
+}
+
+/// Fetches the guest instruction at `gva`, checking execute rather than read
+/// permission.
+pub fn fetch_guest_instruction(vcpu: &VCpu, gva: usize) -> HypervisorResult<u32> {
+    with_guest_privilege(vcpu, || unsafe {
+        // hlvx.hu, instructions are only 2-byte aligned and may cross a page
+        let low: usize = guest_load!(gva, "0x32", "x3")?;
+        if instruction_len(low as u16) == 2 {
+            return Ok(low as u32);
+        }
+        let high: usize = guest_load!(gva + 2, "0x32", "x3")?;
+        Ok((high << 16 | low) as u32)
+    })
+}
+
+/// Recovers from an exception raised by `guest_load!`, returns false if the
+/// trap did not come from a guest memory access.
+pub fn fixup_guest_load(tf: &mut TrapFrame) -> bool {
+    // the hypervisor text is always readable
+    let inst = unsafe { core::ptr::read_unaligned(tf.sepc as *const u32) };
+    let funct7 = inst >> 25;
+    // hlv.{b,bu}, hlv.{h,hu}, hlvx.hu, hlv.{w,wu}, hlvx.wu and hlv.d
+    let is_guest_load = inst & 0x7f == 0x73
+        && (inst >> 12) & 0b111 == 0b100
+        && matches!(funct7, 0x30 | 0x32 | 0x34 | 0x36);
+    if !is_guest_load {
+        return false;
+    }
+    tf.regs.a1 = 1;
+    tf.sepc += 4;
+    true
+}
@@ -0,0 +1,210 @@
+const OPCODE_LOAD: u32 = 0x03;
+const OPCODE_LOAD_FP: u32 = 0x07;
+const OPCODE_STORE: u32 = 0x23;
+const OPCODE_STORE_FP: u32 = 0x27;
+const OPCODE_SYSTEM: u32 = 0x73;
+
+const WFI: u32 = 0x1050_0073;
+
+/// A guest instruction the hypervisor needs to emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Integer load of `width` bytes into `rd`.
+    Load {
+        width: usize,
+        signed: bool,
+        rd: usize,
+    },
+    /// Integer store of the low `width` bytes of `rs2`.
+    Store {
+        width: usize,
+        rs2: usize,
+    },
+    /// Floating-point load of `width` bytes into `f{rd}`.
+    FpLoad {
+        width: usize,
+        rd: usize,
+    },
+    /// Floating-point store of the low `width` bytes of `f{rs2}`.
+    FpStore {
+        width: usize,
+        rs2: usize,
+    },
+    /// Zicsr instruction.
+    Csr {
+        op: CsrOp,
+        csr: usize,
+        rd: usize,
+        src: CsrSource,
+    },
+    Wfi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    /// `csrrw`, `csrrwi`.
+    Write,
+    /// `csrrs`, `csrrsi`.
+    Set,
+    /// `csrrc`, `csrrci`.
+    Clear,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrSource {
+    Reg(usize),
+    /// 5-bit zero extended immediate.
+    Imm(usize),
+}
+
+/// Length of an instruction in bytes given its lowest 16 bits.
+pub fn instruction_len(low: u16) -> usize {
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Decodes the RV64GC integer and floating-point loads and stores, CSR
+/// instructions and `wfi`, `None` for any other instruction.
+///
+/// Only the low 16 bits of `inst` are looked at for compressed instructions.
+pub fn decode(inst: u32) -> Option<Instruction> {
+    if instruction_len(inst as u16) == 2 {
+        return decode_compressed(inst as u16);
+    }
+    let rd = bits(inst, 7, 5) as usize;
+    let funct3 = bits(inst, 12, 3);
+    let rs1 = bits(inst, 15, 5) as usize;
+    let rs2 = bits(inst, 20, 5) as usize;
+    match inst & 0x7f {
+        OPCODE_LOAD => {
+            let (width, signed) = match funct3 {
+                0 => (1, true),
+                1 => (2, true),
+                2 => (4, true),
+                3 => (8, false),
+                4 => (1, false),
+                5 => (2, false),
+                6 => (4, false),
+                _ => return None,
+            };
+            Some(Instruction::Load { width, signed, rd })
+        }
+        OPCODE_STORE => {
+            let width = match funct3 {
+                0..=3 => 1 << funct3,
+                _ => return None,
+            };
+            Some(Instruction::Store { width, rs2 })
+        }
+        // flw, fld
+        OPCODE_LOAD_FP if matches!(funct3, 2 | 3) => Some(Instruction::FpLoad {
+            width: 1 << funct3,
+            rd,
+        }),
+        // fsw, fsd
+        OPCODE_STORE_FP if matches!(funct3, 2 | 3) => Some(Instruction::FpStore {
+            width: 1 << funct3,
+            rs2,
+        }),
+        OPCODE_SYSTEM if inst == WFI => Some(Instruction::Wfi),
+        OPCODE_SYSTEM => {
+            let op = match funct3 & 0b11 {
+                1 => CsrOp::Write,
+                2 => CsrOp::Set,
+                3 => CsrOp::Clear,
+                _ => return None,
+            };
+            let src = if funct3 & 0b100 != 0 {
+                CsrSource::Imm(rs1)
+            } else {
+                CsrSource::Reg(rs1)
+            };
+            Some(Instruction::Csr {
+                op,
+                csr: (inst >> 20) as usize,
+                rd,
+                src,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn decode_compressed(inst: u16) -> Option<Instruction> {
+    let inst = inst as u32;
+    let funct3 = bits(inst, 13, 3);
+    // registers x8-x15 encoded in 3 bits
+    let rd_prime = bits(inst, 2, 3) as usize + 8;
+    let rd = bits(inst, 7, 5) as usize;
+    match (inst & 0b11, funct3) {
+        // c.fld
+        (0b00, 0b001) => Some(Instruction::FpLoad {
+            width: 8,
+            rd: rd_prime,
+        }),
+        // c.fsd
+        (0b00, 0b101) => Some(Instruction::FpStore {
+            width: 8,
+            rs2: rd_prime,
+        }),
+        // c.lw
+        (0b00, 0b010) => Some(Instruction::Load {
+            width: 4,
+            signed: true,
+            rd: rd_prime,
+        }),
+        // c.ld
+        (0b00, 0b011) => Some(Instruction::Load {
+            width: 8,
+            signed: false,
+            rd: rd_prime,
+        }),
+        // c.sw
+        (0b00, 0b110) => Some(Instruction::Store {
+            width: 4,
+            rs2: rd_prime,
+        }),
+        // c.sd
+        (0b00, 0b111) => Some(Instruction::Store {
+            width: 8,
+            rs2: rd_prime,
+        }),
+        // c.lwsp, rd = 0 is reserved
+        (0b10, 0b010) if rd != 0 => Some(Instruction::Load {
+            width: 4,
+            signed: true,
+            rd,
+        }),
+        // c.ldsp, rd = 0 is reserved
+        (0b10, 0b011) if rd != 0 => Some(Instruction::Load {
+            width: 8,
+            signed: false,
+            rd,
+        }),
+        // c.fldsp
+        (0b10, 0b001) => Some(Instruction::FpLoad { width: 8, rd }),
+        // c.fsdsp
+        (0b10, 0b101) => Some(Instruction::FpStore {
+            width: 8,
+            rs2: bits(inst, 2, 5) as usize,
+        }),
+        // c.swsp
+        (0b10, 0b110) => Some(Instruction::Store {
+            width: 4,
+            rs2: bits(inst, 2, 5) as usize,
+        }),
+        // c.sdsp
+        (0b10, 0b111) => Some(Instruction::Store {
+            width: 8,
+            rs2: bits(inst, 2, 5) as usize,
+        }),
+        _ => None,
+    }
+}
+
+fn bits(inst: u32, start: u32, len: u32) -> u32 {
+    (inst >> start) & ((1 << len) - 1)
+}
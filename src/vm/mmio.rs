@@ -0,0 +1,114 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::device::MmioDevice;
+use crate::error::{HypervisorError, HypervisorResult};
+use crate::mem::GuestPhysAddr;
+
+use super::{decode, Instruction};
+
+/// A guest load or store to emulated device memory, decoded from `htinst`.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    /// Access width in bytes.
+    pub width: usize,
+    pub is_write: bool,
+    /// Destination register of a load or source register of a store.
+    pub reg: usize,
+    /// Whether a load is sign extended.
+    pub signed: bool,
+    /// Length of the trapped instruction, 2 if compressed.
+    pub inst_len: usize,
+}
+
+impl MmioAccess {
+    /// Decodes the transformed instruction the hart writes to `htinst` on guest
+    /// page faults, `None` if it is not a plain load or store.
+    pub fn from_htinst(htinst: usize) -> Option<Self> {
+        let inst = htinst as u32;
+        if inst == 0 {
+            return None;
+        }
+        // bit 1 is cleared in the transformed instruction if the original one was
+        // compressed
+        let inst_len = if inst & 0b10 != 0 { 4 } else { 2 };
+        Self::from_instruction(decode(inst | 0b10)?, inst_len)
+    }
+
+    /// Builds the access from a guest instruction decoded by the hypervisor.
+    pub fn from_instruction(inst: Instruction, inst_len: usize) -> Option<Self> {
+        let (width, is_write, reg, signed) = match inst {
+            Instruction::Load { width, signed, rd } => (width, false, rd, signed),
+            Instruction::Store { width, rs2 } => (width, true, rs2, false),
+            // devices are only accessed through integer registers
+            _ => return None,
+        };
+        Some(Self {
+            width,
+            is_write,
+            reg,
+            signed,
+            inst_len,
+        })
+    }
+
+    /// Mask of the bits covered by the access.
+    pub fn mask(&self) -> usize {
+        if self.width == 8 {
+            usize::MAX
+        } else {
+            (1 << (self.width * 8)) - 1
+        }
+    }
+
+    /// Extends a value loaded from the device to register width.
+    pub fn extend(&self, value: usize) -> usize {
+        let value = value & self.mask();
+        let sign_bit = 1 << (self.width * 8 - 1);
+        if self.signed && value & sign_bit != 0 {
+            value | !self.mask()
+        } else {
+            value
+        }
+    }
+}
+
+/// Guest physical address ranges of the devices emulated for a vm.
+#[derive(Default)]
+pub struct MmioBus {
+    regions: Vec<(GuestPhysAddr, Arc<dyn MmioDevice>)>,
+}
+
+impl MmioBus {
+    pub fn register(
+        &mut self,
+        base: GuestPhysAddr,
+        device: Arc<dyn MmioDevice>,
+    ) -> HypervisorResult<()> {
+        let end = base + device.size();
+        let overlaps = self
+            .regions
+            .iter()
+            .any(|(other, dev)| base < *other + dev.size() && *other < end);
+        if overlaps {
+            return Err(HypervisorError::AlreadyMapped);
+        }
+        self.regions.push((base, device));
+        Ok(())
+    }
+
+    /// Base addresses and sizes of the device windows.
+    pub fn ranges(&self) -> impl Iterator<Item = (GuestPhysAddr, usize)> + '_ {
+        self.regions
+            .iter()
+            .map(|(base, device)| (*base, device.size()))
+    }
+
+    /// Returns the device at `gpa` and the offset of `gpa` into its window.
+    pub fn find(&self, gpa: GuestPhysAddr) -> Option<(&dyn MmioDevice, usize)> {
+        self.regions.iter().find_map(|(base, device)| {
+            let offset = gpa.as_usize().checked_sub(base.as_usize())?;
+            (offset < device.size()).then_some((device.as_ref(), offset))
+        })
+    }
+}
@@ -1,11 +1,23 @@
+mod fp;
+mod guest_mem;
+mod insn;
+mod mmio;
 mod vconfig;
+mod vconsole;
 mod vcpu;
+mod vdtb;
 mod vm;
 mod vm_entry;
 mod vm_exit;
 
+pub use fp::*;
+pub use guest_mem::*;
+pub use insn::*;
+pub use mmio::*;
 pub use vconfig::*;
+pub use vconsole::*;
 pub use vcpu::*;
+pub use vdtb::*;
 pub use vm::*;
 pub use vm_entry::*;
 pub use vm_exit::*;
@@ -1,23 +1,61 @@
 use alloc::vec::Vec;
 use log::{debug, info};
+
+use crate::config::PAGE_SIZE_4K;
+use crate::mem::align_down;
+use crate::sbi;
 use serde_derive::Deserialize;
 
+const DEFAULT_UART_IRQ: usize = 10;
+
 #[derive(Debug, Clone)]
 pub struct VMConfig {
     pub name: &'static str,
     pub kernel: &'static str,
+    pub memory_base: usize,
     pub memory_limit: usize,
     pub num_vcpu: usize,
     pub entry: usize,
+    /// SBI specification version reported to the guest, encoded as in
+    /// `sbi_get_spec_version`.
+    pub sbi_spec_version: usize,
+    pub sbi_strict: bool,
+    pub zero_memory_on_reboot: bool,
+    /// Guest physical address of the emulated 16550 UART, none if not present.
+    pub uart_base: Option<usize>,
+    /// Virtual PLIC source of the emulated UART.
+    pub uart_irq: usize,
+    /// Guest physical address of the virtual PLIC, none if not present.
+    pub plic_base: Option<usize>,
+    /// Host PLIC sources routed to the vm, raised on the same source of its
+    /// virtual PLIC.
+    pub irqs: Vec<usize>,
+    /// Kernel command line passed in the guest device tree.
+    pub bootargs: Option<&'static str>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct VMJsonConfig {
     pub name: &'static str,
     pub kernel: &'static str,
+    pub memory_base: Option<&'static str>,
     pub memory_limit: &'static str,
     pub num_vcpu: usize,
     pub entry: &'static str,
+    /// "major.minor", e.g. "2.0".
+    pub sbi_spec_version: Option<&'static str>,
+    /// Terminate the vm instead of returning `SBI_ERR_NOT_SUPPORTED` on SBI calls
+    /// which are not implemented, false by default.
+    pub sbi_strict: Option<bool>,
+    /// Zero guest RAM on cold reboots, false by default. Warm reboots always
+    /// preserve it.
+    pub zero_memory_on_reboot: Option<bool>,
+    pub uart_base: Option<&'static str>,
+    /// 10 by default, as on qemu virt.
+    pub uart_irq: Option<usize>,
+    pub plic_base: Option<&'static str>,
+    pub irqs: Option<Vec<usize>>,
+    pub bootargs: Option<&'static str>,
 }
 
 pub fn vm_configs() -> Vec<VMConfig> {
@@ -25,21 +63,34 @@ pub fn vm_configs() -> Vec<VMConfig> {
         serde_json::from_str(include_str!("../../vm_configs.json")).unwrap();
     let mut vm_configs = Vec::new();
     for vm_json_config in vm_json_configs {
-        let entry_str = vm_json_config
-            .entry
-            .trim_start_matches("0x")
-            .trim_start_matches("0X")
-            .replace("_", "");
-        let entry = usize::from_str_radix(&entry_str, 16).unwrap();
+        let entry = parse_hex(vm_json_config.entry);
+        // guest RAM starts at the page containing the entry unless configured otherwise
+        let memory_base = vm_json_config
+            .memory_base
+            .map(parse_hex)
+            .unwrap_or(align_down(entry, PAGE_SIZE_4K));
 
         let memory_limit = parse_memory_limit(&vm_json_config.memory_limit);
+        let sbi_spec_version = vm_json_config
+            .sbi_spec_version
+            .map(parse_sbi_version)
+            .unwrap_or(sbi::DEFAULT_SPEC_VERSION);
 
         vm_configs.push(VMConfig {
             name: vm_json_config.name,
             kernel: vm_json_config.kernel,
+            memory_base,
             memory_limit,
             num_vcpu: vm_json_config.num_vcpu,
             entry,
+            sbi_spec_version,
+            sbi_strict: vm_json_config.sbi_strict.unwrap_or(false),
+            zero_memory_on_reboot: vm_json_config.zero_memory_on_reboot.unwrap_or(false),
+            uart_base: vm_json_config.uart_base.map(parse_hex),
+            uart_irq: vm_json_config.uart_irq.unwrap_or(DEFAULT_UART_IRQ),
+            plic_base: vm_json_config.plic_base.map(parse_hex),
+            irqs: vm_json_config.irqs.unwrap_or_default(),
+            bootargs: vm_json_config.bootargs,
         });
     }
     info!("[Hypervisor] Parsed VM configs: {:#x?}", vm_configs);
@@ -47,6 +98,25 @@ pub fn vm_configs() -> Vec<VMConfig> {
     vm_configs
 }
 
+fn parse_hex(hex_str: &str) -> usize {
+    let clean_str = hex_str
+        .trim_start_matches("0x")
+        .trim_start_matches("0X")
+        .replace("_", "");
+    usize::from_str_radix(&clean_str, 16).unwrap()
+}
+
+fn parse_sbi_version(version_str: &str) -> usize {
+    let (major, minor) = version_str
+        .trim()
+        .split_once('.')
+        .unwrap_or_else(|| panic!("Unsupported SBI version format: {}", version_str));
+    let major = major.parse::<usize>().unwrap();
+    let minor = minor.parse::<usize>().unwrap();
+    assert!(major < (1 << 7) && minor < (1 << 24));
+    major << 24 | minor
+}
+
 fn parse_memory_limit(size_str: &str) -> usize {
     let clean_str = size_str.trim().to_uppercase();
 
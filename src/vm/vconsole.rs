@@ -0,0 +1,218 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use log::info;
+use spin::{Mutex, Once};
+
+use crate::console;
+
+use super::GLOBAL_VMS;
+
+/// Host console input starting with this byte (Ctrl-A) is meant for the
+/// multiplexer rather than a guest.
+const ESCAPE: u8 = 0x01;
+/// Sent after `ESCAPE` to move input to the next vm.
+const SWITCH_INPUT: u8 = b'n';
+
+const OUTPUT_BUFFER_SIZE: usize = 256;
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// The vm which receives host console input.
+static INPUT_VM: AtomicUsize = AtomicUsize::new(0);
+/// Whether the last host input byte was `ESCAPE`.
+static ESCAPED: Mutex<bool> = Mutex::new(false);
+
+/// Fixed size FIFO of bytes.
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Returns false if the buffer is full.
+    fn push(&mut self, c: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = c;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let c = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(c)
+    }
+}
+
+struct Output {
+    buf: RingBuffer<OUTPUT_BUFFER_SIZE>,
+    /// Whether the last byte written was not a newline, so the next one does
+    /// not start a line.
+    mid_line: bool,
+}
+
+/// Virtual console of a vm, multiplexed onto the host console.
+///
+/// Output is buffered until a newline and prefixed with the vm name, so lines
+/// of different vms do not interleave. Only the vm receiving input has partial
+/// lines written right away, so that its prompts and echoed input show up.
+/// Input goes to one vm at a time, `Ctrl-A n` on the host console moves it to
+/// the next vm.
+pub struct VConsole {
+    vm_id: usize,
+    name: &'static str,
+    output: Mutex<Output>,
+    input: Mutex<RingBuffer<INPUT_BUFFER_SIZE>>,
+    /// Called when input arrives, e.g. to raise the interrupt of a UART.
+    input_hook: Once<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl VConsole {
+    pub fn new(vm_id: usize, name: &'static str) -> Self {
+        Self {
+            vm_id,
+            name,
+            output: Mutex::new(Output {
+                buf: RingBuffer::new(),
+                mid_line: false,
+            }),
+            input: Mutex::new(RingBuffer::new()),
+            input_hook: Once::new(),
+        }
+    }
+
+    /// Sets the hook called without any console lock held when input arrives.
+    pub fn set_input_hook(&self, hook: impl Fn() + Send + Sync + 'static) {
+        self.input_hook.call_once(|| Box::new(hook));
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        let mut output = self.output.lock();
+        for c in bytes {
+            if output.buf.is_full() {
+                self.flush(&mut output);
+            }
+            output.buf.push(*c);
+            if *c == b'\n' {
+                self.flush(&mut output);
+            }
+        }
+        if INPUT_VM.load(Ordering::Relaxed) == self.vm_id {
+            self.flush(&mut output);
+        }
+    }
+
+    /// Reads input without blocking, returns the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        poll_host_input();
+        self.pop_input(buf)
+    }
+
+    /// Reads input already moved to this console, without polling the host.
+    pub fn pop_input(&self, buf: &mut [u8]) -> usize {
+        let mut input = self.input.lock();
+        let mut read = 0;
+        while read < buf.len() {
+            let Some(c) = input.pop() else {
+                break;
+            };
+            buf[read] = c;
+            read += 1;
+        }
+        read
+    }
+
+    fn flush(&self, output: &mut Output) {
+        if output.buf.is_empty() {
+            return;
+        }
+        let mut line = if output.mid_line {
+            Vec::new()
+        } else {
+            format!("[{}] ", self.name).into_bytes()
+        };
+        while let Some(c) = output.buf.pop() {
+            line.push(c);
+        }
+        output.mid_line = line.last() != Some(&b'\n');
+        console::print_bytes(&line);
+    }
+}
+
+/// Moves pending host console input to the vm currently receiving it, and calls
+/// the input hooks of the vms which received some.
+pub fn poll_host_input() {
+    let mut received = Vec::new();
+    {
+        let mut escaped = ESCAPED.lock();
+        while let Some(c) = console::getchar() {
+            if *escaped {
+                *escaped = false;
+                match c {
+                    SWITCH_INPUT => switch_input_vm(),
+                    // `Ctrl-A Ctrl-A` sends a literal `Ctrl-A`
+                    ESCAPE => push_input(ESCAPE, &mut received),
+                    _ => {}
+                }
+            } else if c == ESCAPE {
+                *escaped = true;
+            } else {
+                push_input(c, &mut received);
+            }
+        }
+    }
+    let vms = unsafe { GLOBAL_VMS.get_unchecked() };
+    for vm_id in received {
+        if let Some(hook) = vms[vm_id].console.input_hook.get() {
+            hook();
+        }
+    }
+}
+
+fn push_input(c: u8, received: &mut Vec<usize>) {
+    let vms = unsafe { GLOBAL_VMS.get_unchecked() };
+    let vm_id = INPUT_VM.load(Ordering::Relaxed);
+    // input is dropped if the guest does not read it
+    vms[vm_id].console.input.lock().push(c);
+    if !received.contains(&vm_id) {
+        received.push(vm_id);
+    }
+}
+
+fn switch_input_vm() {
+    let vms = unsafe { GLOBAL_VMS.get_unchecked() };
+    let next = (INPUT_VM.load(Ordering::Relaxed) + 1) % vms.len();
+    INPUT_VM.store(next, Ordering::Relaxed);
+    info!(
+        "[Hypervisor] console input goes to vm {}: {}",
+        next, vms[next].name
+    );
+    // show the prompt the vm may be waiting at
+    let console = &vms[next].console;
+    console.flush(&mut console.output.lock());
+}
@@ -1,25 +1,152 @@
+use core::mem::offset_of;
 use core::ops::{Deref, DerefMut};
-use core::{mem::offset_of, sync::atomic::AtomicUsize};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-static VCPU_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
+use crate::csr;
+
+use super::{FpState, _restore_fp_state, _save_fp_state};
 
 #[derive(Debug)]
 #[repr(C)]
 pub struct VCpu {
+    /// Index of this vcpu within its VM.
     pub vcpu_id: usize,
     pub hyp_cpu_state: HypervisorCpuState,
     pub guest_cpu_state: GuestCpuState,
+    pub vs_csrs: VsCsrs,
+    pub fp_state: FpState,
+    /// Whether the VS-level CSRs and floating-point registers in hardware are more
+    /// recent than `vs_csrs` and `fp_state`, which is the case between running the
+    /// vcpu and switching to another one.
+    pub context_live: bool,
+    /// Hart this vcpu last ran on, which is the only one whose VS-stage TLB
+    /// entries for it are kept up to date by the guest's own fences.
+    pub last_hart: Option<usize>,
 }
 
 impl VCpu {
-    pub fn new() -> Self {
+    pub fn new(vcpu_id: usize) -> Self {
         Self {
-            vcpu_id: VCPU_ID_GENERATOR.fetch_add(1, core::sync::atomic::Ordering::SeqCst),
+            vcpu_id,
             hyp_cpu_state: HypervisorCpuState::default(),
             guest_cpu_state: GuestCpuState::default(),
+            vs_csrs: VsCsrs::default(),
+            fp_state: FpState::default(),
+            context_live: false,
+            last_hart: None,
         }
     }
 
+    /// Resets the guest context so the vcpu starts at `entry` in VS-mode with
+    /// `a0 = hartid` and `a1 = opaque`, as SBI HSM `hart_start` requires.
+    pub fn reset(&mut self, entry: usize, opaque: usize) {
+        self.guest_cpu_state = GuestCpuState::default();
+        // discard whatever the vcpu left in hardware
+        self.vs_csrs = VsCsrs {
+            vstimecmp: usize::MAX,
+            ..Default::default()
+        };
+        self.fp_state = FpState::default();
+        self.context_live = false;
+        self.last_hart = None;
+        self.guest_cpu_state.gprs[10] = self.vcpu_id;
+        self.guest_cpu_state.gprs[11] = opaque;
+
+        let mut hstatus = csr::Hstatus::read();
+        hstatus.set_spv(true);
+        hstatus.set_spvp(true);
+        // trap guest `wfi` so the pcpu can run other vcpus meanwhile
+        hstatus.set_vtw(true);
+        self.guest_cpu_state.hstatus = hstatus.bits();
+
+        let mut sstatus = csr::Sstatus::read();
+        sstatus.set_spp(true);
+        // the guest may use the FPU, but vector state is not switched
+        sstatus.set_fs(csr::ExtensionState::Initial);
+        sstatus.set_vs(csr::ExtensionState::Off);
+        self.guest_cpu_state.sstatus = sstatus.bits();
+
+        self.guest_cpu_state.sepc = entry;
+    }
+
+    /// Saves the context which stays in hardware across vm exits, done lazily
+    /// when the pcpu switches to another vcpu.
+    pub fn save_context(&mut self) {
+        self.save_vs_csrs();
+        self.save_fp_state();
+        self.context_live = false;
+    }
+
+    /// Loads the context saved by `save_context` into hardware.
+    pub fn restore_context(&mut self) {
+        self.restore_vs_csrs();
+        self.restore_fp_state();
+        self.context_live = true;
+    }
+
+    /// Saves the floating-point registers only if the guest dirtied them.
+    fn save_fp_state(&mut self) {
+        let mut sstatus = csr::Sstatus::from_bits(self.guest_cpu_state.sstatus);
+        if sstatus.fs() != csr::ExtensionState::Dirty {
+            return;
+        }
+        with_fpu_enabled(|| unsafe { _save_fp_state(&mut self.fp_state) });
+        sstatus.set_fs(csr::ExtensionState::Clean);
+        self.guest_cpu_state.sstatus = sstatus.bits();
+    }
+
+    fn restore_fp_state(&mut self) {
+        with_fpu_enabled(|| unsafe { _restore_fp_state(&self.fp_state) });
+    }
+
+    fn save_vs_csrs(&mut self) {
+        let csrs = &mut self.vs_csrs;
+        csrs.vsstatus = csr::Vsstatus::read().bits();
+        csrs.vsie = csr::vsie::read();
+        csrs.vstvec = csr::vstvec::read();
+        csrs.vsscratch = csr::vsscratch::read();
+        csrs.vsepc = csr::vsepc::read();
+        csrs.vscause = csr::vscause::read();
+        csrs.vstval = csr::vstval::read();
+        csrs.vsatp = csr::vsatp::read();
+        csrs.hvip = csr::Hvip::read().bits();
+        csrs.htimedelta = csr::htimedelta::read();
+        if csr::sstc_supported() {
+            csrs.vstimecmp = csr::vstimecmp::read();
+        }
+    }
+
+    fn restore_vs_csrs(&mut self) {
+        let csrs = &self.vs_csrs;
+        csr::Vsstatus::from_bits(csrs.vsstatus).write();
+        csr::vsie::write(csrs.vsie);
+        csr::vstvec::write(csrs.vstvec);
+        csr::vsscratch::write(csrs.vsscratch);
+        csr::vsepc::write(csrs.vsepc);
+        csr::vscause::write(csrs.vscause);
+        csr::vstval::write(csrs.vstval);
+        csr::vsatp::write(csrs.vsatp);
+        csr::Hvip::from_bits(csrs.hvip).write();
+        csr::htimedelta::write(csrs.htimedelta);
+        if csr::sstc_supported() {
+            csr::vstimecmp::write(csrs.vstimecmp);
+        }
+    }
+
+    /// Converts a time as seen by the guest, which is offset by `htimedelta`,
+    /// to hypervisor time. `u64::MAX` stays the "never" deadline.
+    pub fn host_time(&self, guest_time: u64) -> u64 {
+        if guest_time == u64::MAX {
+            return u64::MAX;
+        }
+        let htimedelta = if self.context_live {
+            csr::htimedelta::read()
+        } else {
+            self.vs_csrs.htimedelta
+        };
+        guest_time.wrapping_sub(htimedelta as u64)
+    }
+
     pub const fn hyp_gpr_offset(index: usize) -> usize {
         assert!(index < 32);
         offset_of!(VCpu, hyp_cpu_state) + offset_of!(HypervisorCpuState, gprs) + index * 8
@@ -31,6 +158,141 @@ impl VCpu {
     }
 }
 
+/// Runs `f` with the FPU accessible to the hypervisor.
+fn with_fpu_enabled(f: impl FnOnce()) {
+    let sstatus = csr::Sstatus::read();
+    let mut enabled = sstatus;
+    enabled.set_fs(csr::ExtensionState::Initial);
+    enabled.write();
+    f();
+    sstatus.write();
+}
+
+/// Per-vcpu state which other pcpus may access while the vcpu itself is locked
+/// by the pcpu running it.
+#[derive(Debug)]
+pub struct VCpuControl {
+    /// SBI HSM state, one of `sbi_spec::hsm::hart_state`.
+    hart_state: AtomicUsize,
+    /// The pcpu this vcpu is bound to.
+    pcpu_id: AtomicUsize,
+    /// Hypervisor time at which the guest asked for a timer interrupt, `u64::MAX`
+    /// if none.
+    timer_deadline: AtomicU64,
+    /// An IPI was sent to the vcpu and has not been injected yet.
+    ipi_pending: AtomicBool,
+    /// The S-mode context of the vcpu in the virtual PLIC has a claimable interrupt.
+    external_pending: AtomicBool,
+    /// Fences requested by other vcpus, `FENCE_I | SFENCE_VMA | HFENCE_GVMA`.
+    fence_requests: AtomicUsize,
+    /// Whether a pcpu has the vcpu loaded and is between two vm entries.
+    running: AtomicBool,
+}
+
+/// `fence.i` requested through SBI RFENCE.
+pub const FENCE_I: usize = 1 << 0;
+/// Flushing all guest translations requested through SBI RFENCE.
+pub const SFENCE_VMA: usize = 1 << 1;
+/// Flushing the G-stage translations of the vm after its page table changed.
+pub const HFENCE_GVMA: usize = 1 << 2;
+
+impl VCpuControl {
+    pub fn new(hart_state: usize) -> Self {
+        Self {
+            hart_state: AtomicUsize::new(hart_state),
+            pcpu_id: AtomicUsize::new(0),
+            timer_deadline: AtomicU64::new(u64::MAX),
+            ipi_pending: AtomicBool::new(false),
+            external_pending: AtomicBool::new(false),
+            fence_requests: AtomicUsize::new(0),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    pub fn pcpu_id(&self) -> usize {
+        self.pcpu_id.load(Ordering::Acquire)
+    }
+
+    pub fn set_pcpu_id(&self, pcpu_id: usize) {
+        self.pcpu_id.store(pcpu_id, Ordering::Release);
+    }
+
+    pub fn timer_deadline(&self) -> u64 {
+        self.timer_deadline.load(Ordering::Acquire)
+    }
+
+    pub fn set_timer_deadline(&self, deadline: u64) {
+        self.timer_deadline.store(deadline, Ordering::Release);
+    }
+
+    pub fn set_ipi_pending(&self) {
+        self.ipi_pending.store(true, Ordering::Release);
+    }
+
+    /// Returns whether an IPI was pending and clears it.
+    pub fn take_ipi_pending(&self) -> bool {
+        self.ipi_pending.swap(false, Ordering::AcqRel)
+    }
+
+    pub fn ipi_pending(&self) -> bool {
+        self.ipi_pending.load(Ordering::Acquire)
+    }
+
+    pub fn external_pending(&self) -> bool {
+        self.external_pending.load(Ordering::Acquire)
+    }
+
+    pub fn set_external_pending(&self, pending: bool) {
+        self.external_pending.store(pending, Ordering::Release);
+    }
+
+    pub fn request_fence(&self, fence: usize) {
+        self.fence_requests.fetch_or(fence, Ordering::AcqRel);
+    }
+
+    /// Returns the requested fences and clears them.
+    pub fn take_fence_requests(&self) -> usize {
+        self.fence_requests.swap(0, Ordering::AcqRel)
+    }
+
+    pub fn fence_pending(&self) -> bool {
+        self.fence_requests.load(Ordering::Acquire) != 0
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn set_running(&self, running: bool) {
+        self.running.store(running, Ordering::SeqCst);
+    }
+
+    /// Forgets everything pending for the vcpu, e.g. when its vm reboots.
+    pub fn reset(&self, hart_state: usize) {
+        self.set_timer_deadline(u64::MAX);
+        self.ipi_pending.store(false, Ordering::Release);
+        self.set_external_pending(false);
+        self.fence_requests.store(0, Ordering::Release);
+        self.set_hart_state(hart_state);
+    }
+
+    pub fn hart_state(&self) -> usize {
+        self.hart_state.load(Ordering::Acquire)
+    }
+
+    pub fn set_hart_state(&self, state: usize) {
+        self.hart_state.store(state, Ordering::Release);
+    }
+
+    /// Atomically moves the vcpu from `current` to `new` HSM state, returns false if
+    /// the vcpu was not in `current` state.
+    pub fn transit_hart_state(&self, current: usize, new: usize) -> bool {
+        self.hart_state
+            .compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
 #[macro_export]
 macro_rules! vcpu_hyp_csr_offset {
     ($reg:tt) => {
@@ -56,6 +318,24 @@ pub struct GuestCpuState {
     pub sepc: usize,
 }
 
+/// VS-level CSRs, plus the hypervisor CSRs which only affect the guest, which stay
+/// live in hardware across `_vm_entry`/`_vm_exit` and are switched by the scheduler.
+#[derive(Default, Debug)]
+pub struct VsCsrs {
+    pub vsstatus: usize,
+    pub vsie: usize,
+    pub vstvec: usize,
+    pub vsscratch: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+    pub vsatp: usize,
+    pub hvip: usize,
+    pub htimedelta: usize,
+    /// Only used with Sstc.
+    pub vstimecmp: usize,
+}
+
 #[derive(Default, Debug)]
 #[repr(C)]
 pub struct HypervisorCpuState {
@@ -0,0 +1,285 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::csr;
+use crate::device::{UART16550_SIZE, VPLIC_NUM_SOURCES};
+use crate::dtb::MachineMeta;
+
+use super::{MmioBus, VMConfig};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+/// The memory reservation block, only its terminating empty entry.
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// `interrupts-extended` causes of the hart-local interrupt controller.
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Input clock of the qemu virt 16550, drivers need one to set up the divisor.
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+/// Writes a flattened device tree, nodes are opened and closed in order.
+#[derive(Default)]
+struct FdtWriter {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: BTreeMap<&'static str, u32>,
+}
+
+impl FdtWriter {
+    fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    fn property(&mut self, name: &'static str, value: &[u8]) {
+        let name_offset = match self.string_offsets.get(name) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.string_offsets.insert(name, offset);
+                offset
+            }
+        };
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structs.extend_from_slice(value);
+        self.align();
+    }
+
+    fn property_null(&mut self, name: &'static str) {
+        self.property(name, &[]);
+    }
+
+    fn property_u32(&mut self, name: &'static str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    fn property_cells(&mut self, name: &'static str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    /// `reg` of a single range with 2 address and 2 size cells.
+    fn property_reg(&mut self, base: usize, size: usize) {
+        let value: Vec<u8> = [base as u64, size as u64]
+            .iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect();
+        self.property("reg", &value);
+    }
+
+    fn property_string(&mut self, name: &'static str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    fn property_strings(&mut self, name: &'static str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structs.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structs.len() % 4 != 0 {
+            self.structs.push(0);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structs.len();
+        let total_size = off_dt_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ];
+        let mut fdt = Vec::with_capacity(total_size);
+        fdt.extend(header.iter().flat_map(|field| field.to_be_bytes()));
+        fdt.resize(off_dt_struct, 0);
+        fdt.extend_from_slice(&self.structs);
+        fdt.extend_from_slice(&self.strings);
+        fdt
+    }
+}
+
+/// Builds the device tree passed to the guest in `a1`, describing its RAM,
+/// vcpus and devices.
+pub fn build_vm_dtb(vm_config: &VMConfig, meta: &MachineMeta, mmio_bus: &MmioBus) -> Vec<u8> {
+    let num_vcpu = vm_config.num_vcpu as u32;
+    let intc_phandle = |vcpu_id: u32| vcpu_id + 1;
+    let plic_phandle = num_vcpu + 1;
+    let host_isa = meta
+        .harts
+        .first()
+        .map(|hart| hart.isa.as_str())
+        .filter(|isa| !isa.is_empty())
+        .unwrap_or("rv64imafdc");
+    let isa = virtualized_isa(host_isa);
+
+    let mut fdt = FdtWriter::default();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", &format!("riscv-hypervisor,{}", vm_config.name));
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = vm_config.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some(uart_base) = vm_config.uart_base {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart_base));
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", vm_config.memory_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_reg(vm_config.memory_base, vm_config.memory_limit);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", meta.timebase_frequency as u32);
+    for vcpu_id in 0..num_vcpu {
+        fdt.begin_node(&format!("cpu@{:x}", vcpu_id));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", vcpu_id);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc_phandle(vcpu_id));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    let plic = vm_config
+        .plic_base
+        .and_then(|base| Some((base, mmio_bus.find(base.into())?.0.size())));
+    if let Some((plic_base, plic_size)) = plic {
+        fdt.begin_node(&format!("plic@{:x}", plic_base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(plic_base, plic_size);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,ndev", (VPLIC_NUM_SOURCES - 1) as u32);
+        // context 2n is the M-mode and 2n + 1 the S-mode context of vcpu n
+        let contexts: Vec<u32> = (0..num_vcpu)
+            .flat_map(|vcpu_id| {
+                let intc = intc_phandle(vcpu_id);
+                [intc, IRQ_M_EXT, intc, IRQ_S_EXT]
+            })
+            .collect();
+        fdt.property_cells("interrupts-extended", &contexts);
+        fdt.property_u32("phandle", plic_phandle);
+        fdt.end_node();
+    }
+
+    if let Some(uart_base) = vm_config.uart_base {
+        fdt.begin_node(&format!("serial@{:x}", uart_base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(uart_base, UART16550_SIZE);
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        if plic.is_some() {
+            fdt.property_u32("interrupts", vm_config.uart_irq as u32);
+            fdt.property_u32("interrupt-parent", plic_phandle);
+        }
+        fdt.end_node();
+    }
+
+    // passed through by `map_passthrough_devices`
+    for dev in meta.virtio.iter() {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", dev.base_address));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(dev.base_address, dev.size);
+        // the guest polls devices whose interrupt is not routed to it
+        let irq = dev.irq.filter(|irq| vm_config.irqs.contains(irq));
+        if let (Some(irq), Some(_)) = (irq, plic) {
+            fdt.property_u32("interrupts", irq as u32);
+            fdt.property_u32("interrupt-parent", plic_phandle);
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// The host ISA string without the extensions guests cannot use: the hypervisor
+/// extension, vector (its state is not switched), and those which need enabling
+/// through `henvcfg` or belong to M-mode.
+fn virtualized_isa(host_isa: &str) -> String {
+    let mut extensions = host_isa.split('_');
+    let base = extensions.next().unwrap_or_default();
+    let mut isa: String = base
+        .char_indices()
+        .filter(|(i, c)| *i < 4 || !matches!(c, 'h' | 'v'))
+        .map(|(_, c)| c)
+        .collect();
+    for ext in extensions {
+        let virtualized = match ext {
+            "sstc" => csr::sstc_supported(),
+            "svinval" => true,
+            _ if ext.starts_with("zicbo") || ext.starts_with("zicfi") || ext.starts_with("zv") => {
+                false
+            }
+            _ => ext.starts_with('z'),
+        };
+        if virtualized {
+            isa.push('_');
+            isa.push_str(ext);
+        }
+    }
+    isa
+}
@@ -1,18 +1,22 @@
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::allocator::PHYS_FRAME_ALLOCATOR;
-use crate::config::PAGE_SIZE_4K;
+use crate::config::{GUEST_MEMORY_CHUNK_SIZE, PAGE_SIZE_4K};
+use crate::device::{is_valid_source, IrqLine, Uart16550, VPlic};
 use crate::dtb::MachineMeta;
-use crate::error::HypervisorResult;
-use crate::pcpu::GLOBAL_PCPUS;
+use crate::error::{HypervisorError, HypervisorResult};
+use crate::pcpu::{VCpuExit, GLOBAL_PCPUS};
+use crate::{plic, sbi, sched};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use log::debug;
+use log::{debug, info};
+use sbi_spec::hsm::hart_state;
 use spin::{Mutex, Once};
 
 use crate::mem::{align_down, align_up, GuestPageTable, GuestPhysAddr, HostPhysAddr, PTEFlags};
-use crate::vm::{self, kernel_image, vconfig, VMConfig};
+use crate::vm::{kernel_image, vconfig, VMConfig};
 
-use super::VCpu;
+use super::{build_vm_dtb, MmioBus, VConsole, VCpu, VCpuControl, FENCE_I, HFENCE_GVMA, SFENCE_VMA};
 
 pub static GLOBAL_VMS: Once<Vec<VM>> = Once::new();
 pub static VM_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
@@ -20,13 +24,18 @@ pub static VM_ID_GENERATOR: AtomicUsize = AtomicUsize::new(0);
 pub fn init_vms(meta: &MachineMeta) {
     let vm_configs = vconfig::vm_configs();
     let mut vms = Vec::new();
-    let vm_config = vm_configs.get(0).unwrap();
-    let vm = VM::new(vm_config.clone(), meta).expect("Failed to create VM");
-    vms.push(vm);
-    // for vm_config in vm_configs {
-    //     let vm = VM::new(vm_config, meta).expect("Failed to create VM");
-    //     vms.push(vm);
-    // }
+    for vm_config in vm_configs {
+        let vm = VM::new(vm_config, meta).expect("Failed to create VM");
+        info!(
+            "[Hypervisor] created vm {}: {}, guest memory: [{:?}, {:?}) -> {:#x?}",
+            vm.vm_id,
+            vm.name,
+            vm.memory_base,
+            vm.memory_base + vm.memory_limit,
+            vm.memory_regions.lock()
+        );
+        vms.push(vm);
+    }
     GLOBAL_VMS.call_once(|| vms);
 }
 
@@ -48,9 +57,9 @@ pub fn bind_vcpus() {
 fn bind_vcpu_to_pcpu(vm_id: usize, vcpu_id: usize, pcpu_id: usize) {
     unsafe {
         let pcpu = GLOBAL_PCPUS.get_unchecked().get_unchecked(pcpu_id);
-        let mut vcpus = pcpu.vcpus.lock();
-        assert!(!vcpus.contains(&(vm_id, vcpu_id)));
-        vcpus.push((vm_id, vcpu_id));
+        let vm = GLOBAL_VMS.get_unchecked().get_unchecked(vm_id);
+        vm.vcpu_ctrls[vcpu_id].set_pcpu_id(pcpu_id);
+        pcpu.run_queue.lock().bind((vm_id, vcpu_id));
         debug!(
             "[Hypervisor] bind vm {} vcpu {} to pcpu {}",
             vm_id, vcpu_id, pcpu_id
@@ -58,67 +67,404 @@ fn bind_vcpu_to_pcpu(vm_id: usize, vcpu_id: usize, pcpu_id: usize) {
     }
 }
 
+/// Host frames backing a contiguous range of guest physical memory.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestMemoryRegion {
+    pub gpa: GuestPhysAddr,
+    pub hpa: HostPhysAddr,
+    pub size: usize,
+}
+
+/// The vm runs normally.
+const VM_RUNNING: usize = 0;
+/// A vcpu is rebooting the vm, the others must not run.
+const VM_REBOOTING: usize = 1;
+/// The vm was shut down for good.
+const VM_TERMINATED: usize = 2;
+
 pub struct VM {
     pub vm_id: usize,
+    pub name: &'static str,
     pub vcpus: Vec<Mutex<VCpu>>,
-    pub guest_page_table: GuestPageTable,
+    pub vcpu_ctrls: Vec<VCpuControl>,
+    pub guest_page_table: Mutex<GuestPageTable>,
+    pub memory_regions: Mutex<Vec<GuestMemoryRegion>>,
     pub kernel_image: &'static [u8],
+    /// Device tree of the vm, passed to the boot vcpu in `a1`.
+    pub dtb: Vec<u8>,
+    pub dtb_base: GuestPhysAddr,
+    pub memory_base: GuestPhysAddr,
     pub memory_limit: usize,
     pub entry: GuestPhysAddr,
+    pub sbi_spec_version: usize,
+    /// Terminate the vm on SBI calls it does not implement instead of failing them.
+    pub sbi_strict: bool,
+    pub zero_memory_on_reboot: bool,
+    pub console: Arc<VConsole>,
+    /// Devices left unmapped in the guest page table, accesses to them trap.
+    pub mmio_bus: MmioBus,
+    pub vplic: Option<Arc<VPlic>>,
+    /// Host PLIC sources routed to the vm.
+    pub passthrough_irqs: Vec<usize>,
+    state: AtomicUsize,
 }
 
 impl VM {
     pub fn new(vm_config: VMConfig, meta: &MachineMeta) -> HypervisorResult<Self> {
+        let vm_id = VM_ID_GENERATOR.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
         let kernel_image = kernel_image(vm_config.kernel);
-        let guest_page_table = init_guest_page_table(&vm_config, meta)?;
+        let console = Arc::new(VConsole::new(vm_id, vm_config.name));
+        let mut mmio_bus = MmioBus::default();
+        let vplic = match vm_config.plic_base {
+            Some(plic_base) => {
+                let vplic = Arc::new(VPlic::new(vm_id, vm_config.num_vcpu, &vm_config.irqs)?);
+                mmio_bus.register(plic_base.into(), vplic.clone())?;
+                Some(vplic)
+            }
+            // host interrupts can only be forwarded through a virtual PLIC
+            None if !vm_config.irqs.is_empty() => return Err(HypervisorError::InvalidParam),
+            None => None,
+        };
+        if let Some(uart_base) = vm_config.uart_base {
+            // the UART source must not be one of the host sources routed to the vm
+            let uart_irq = vm_config.uart_irq;
+            if vplic.is_some() && (!is_valid_source(uart_irq) || vm_config.irqs.contains(&uart_irq))
+            {
+                return Err(HypervisorError::InvalidParam);
+            }
+            let irq = vplic
+                .as_ref()
+                .map(|vplic| IrqLine::new(vplic.clone(), vm_config.uart_irq));
+            let uart = Arc::new(Uart16550::new(console.clone(), irq));
+            let weak_uart = Arc::downgrade(&uart);
+            console.set_input_hook(move || {
+                if let Some(uart) = weak_uart.upgrade() {
+                    uart.input_available();
+                }
+            });
+            mmio_bus.register(uart_base.into(), uart)?;
+        }
+        check_mmio_regions(&vm_config, &mmio_bus)?;
+        let mut guest_page_table = GuestPageTable::try_new()?;
+        let memory_regions = init_guest_memory(&vm_config, &mut guest_page_table)?;
+        load_image(kernel_image, vm_config.entry.into(), &mut guest_page_table)?;
+        let dtb = build_vm_dtb(&vm_config, meta, &mmio_bus);
+        let dtb_base = dtb_base(&vm_config, kernel_image.len(), dtb.len())?;
+        load_image(&dtb, dtb_base, &mut guest_page_table)?;
+        map_passthrough_devices(meta, &mut guest_page_table)?;
         let mut vcpus = Vec::new();
-        for _ in 0..vm_config.num_vcpu {
-            vcpus.push(Mutex::new(VCpu::new()));
+        let mut vcpu_ctrls = Vec::new();
+        for vcpu_id in 0..vm_config.num_vcpu {
+            let mut vcpu = VCpu::new(vcpu_id);
+            // only the boot vcpu runs, the others wait for SBI HSM `hart_start`
+            let hart_state = if vcpu_id == 0 {
+                vcpu.reset(vm_config.entry, dtb_base.as_usize());
+                hart_state::STARTED
+            } else {
+                hart_state::STOPPED
+            };
+            vcpus.push(Mutex::new(vcpu));
+            vcpu_ctrls.push(VCpuControl::new(hart_state));
         }
         Ok(Self {
-            vm_id: VM_ID_GENERATOR.fetch_add(1, core::sync::atomic::Ordering::SeqCst),
+            vm_id,
+            name: vm_config.name,
             vcpus,
-            guest_page_table,
+            vcpu_ctrls,
+            guest_page_table: Mutex::new(guest_page_table),
+            memory_regions: Mutex::new(memory_regions),
             kernel_image,
+            dtb,
+            dtb_base,
+            memory_base: vm_config.memory_base.into(),
             memory_limit: vm_config.memory_limit,
             entry: vm_config.entry.into(),
+            sbi_spec_version: vm_config.sbi_spec_version,
+            sbi_strict: vm_config.sbi_strict,
+            zero_memory_on_reboot: vm_config.zero_memory_on_reboot,
+            console,
+            mmio_bus,
+            vplic,
+            passthrough_irqs: vm_config.irqs,
+            state: AtomicUsize::new(VM_RUNNING),
         })
     }
+
+    /// Stops every vcpu of this vm for good and frees its memory, other vms keep
+    /// running. `caller` is the vcpu asking for it, which must halt afterwards.
+    pub fn shutdown(&self, caller: usize) {
+        if self.state.swap(VM_TERMINATED, Ordering::SeqCst) == VM_TERMINATED {
+            return;
+        }
+        info!("[Hypervisor] shutdown vm {}: {}", self.vm_id, self.name);
+        self.stop_other_vcpus(caller);
+
+        for source in self.passthrough_irqs.iter() {
+            plic::disable_host_irq(*source);
+        }
+        if let Some(vplic) = self.vplic.as_ref() {
+            vplic.reset();
+        }
+        for region in self.memory_regions.lock().drain(..) {
+            // nothing may reach the frames through stale translations once freed
+            self.update_guest_page_table(caller, |guest_page_table| {
+                guest_page_table.unmap_region(region.gpa, region.size / PAGE_SIZE_4K)
+            })
+            .expect("guest RAM is mapped");
+            PHYS_FRAME_ALLOCATOR
+                .lock()
+                .dealloc_frames(region.hpa, region.size / PAGE_SIZE_4K);
+        }
+        self.guest_page_table.lock().clear();
+    }
+
+    /// Restarts this vm from `entry` with only vcpu 0 started. Guest RAM is zeroed
+    /// on cold reboots if configured, and the kernel image is loaded again.
+    ///
+    /// `caller` is the vcpu asking for it, it is put back on the run queue after
+    /// the returned exit and only runs again if it is vcpu 0.
+    pub fn reboot(&self, caller: &mut VCpu, cold: bool) -> HypervisorResult<VCpuExit> {
+        if self
+            .state
+            .compare_exchange(VM_RUNNING, VM_REBOOTING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            // another vcpu got there first
+            return Ok(VCpuExit::Yield);
+        }
+        info!(
+            "[Hypervisor] {} reboot vm {}: {}",
+            if cold { "cold" } else { "warm" },
+            self.vm_id,
+            self.name
+        );
+        for ctrl in self.vcpu_ctrls.iter() {
+            ctrl.set_hart_state(hart_state::STOPPED);
+        }
+        self.stop_other_vcpus(caller.vcpu_id);
+
+        {
+            let mut guest_page_table = self.guest_page_table.lock();
+            if cold && self.zero_memory_on_reboot {
+                for region in self.memory_regions.lock().iter() {
+                    unsafe {
+                        core::ptr::write_bytes(region.hpa.as_usize() as *mut u8, 0, region.size)
+                    };
+                }
+            }
+            load_image(self.kernel_image, self.entry, &mut guest_page_table)?;
+            load_image(&self.dtb, self.dtb_base, &mut guest_page_table)?;
+        }
+        if let Some(vplic) = self.vplic.as_ref() {
+            vplic.reset();
+        }
+
+        for ctrl in self.vcpu_ctrls.iter() {
+            ctrl.reset(hart_state::STOPPED);
+        }
+        let boot_ctrl = &self.vcpu_ctrls[0];
+        if caller.vcpu_id == 0 {
+            caller.reset(self.entry.as_usize(), self.dtb_base.as_usize());
+        } else {
+            self.vcpus[0]
+                .lock()
+                .reset(self.entry.as_usize(), self.dtb_base.as_usize());
+        }
+        // the kernel image was rewritten and guest translations are stale
+        boot_ctrl.request_fence(FENCE_I | SFENCE_VMA);
+        boot_ctrl.set_hart_state(hart_state::STARTED);
+        self.state.store(VM_RUNNING, Ordering::SeqCst);
+        sched::enqueue_vcpu((self.vm_id, 0));
+        Ok(VCpuExit::Yield)
+    }
+
+    /// Changes the guest page table through `f`, e.g. with `unmap_region` or
+    /// `protect_region`, while vcpus of this vm may be running.
+    ///
+    /// The page table only fences this pcpu, where `caller` is loaded. The other
+    /// vcpus flush their G-stage translations before they next enter the guest,
+    /// and those currently running are kicked and waited for.
+    pub fn update_guest_page_table<T>(
+        &self,
+        caller: usize,
+        f: impl FnOnce(&mut GuestPageTable) -> T,
+    ) -> T {
+        let ret = f(&mut self.guest_page_table.lock());
+        let mut running = Vec::new();
+        for (vcpu_id, ctrl) in self.vcpu_ctrls.iter().enumerate() {
+            if vcpu_id == caller {
+                continue;
+            }
+            ctrl.request_fence(HFENCE_GVMA);
+            if ctrl.is_running() {
+                sched::kick_vcpu((self.vm_id, vcpu_id));
+                running.push(vcpu_id);
+            }
+        }
+
+        let caller_ctrl = &self.vcpu_ctrls[caller];
+        let in_flight = |vcpu_id: &usize| {
+            let ctrl = &self.vcpu_ctrls[*vcpu_id];
+            ctrl.fence_pending() && ctrl.is_running()
+        };
+        while running.iter().any(in_flight) {
+            // another vcpu may be waiting for a fence on the caller
+            sbi::flush_requested(caller_ctrl);
+            core::hint::spin_loop();
+        }
+        ret
+    }
+
+    /// Writes guest console output.
+    pub fn console_write(&self, bytes: &[u8]) {
+        self.console.write(bytes);
+    }
+
+    /// Reads guest console input into `buf` without blocking, returns the number
+    /// of bytes read.
+    pub fn console_read(&self, buf: &mut [u8]) -> usize {
+        self.console.read(buf)
+    }
+
+    /// How a pcpu must put back a vcpu of this vm which it is about to run or has
+    /// just run, if the vm does not let vcpus run at the moment.
+    pub fn inactive_exit(&self) -> Option<VCpuExit> {
+        match self.state.load(Ordering::SeqCst) {
+            VM_RUNNING => None,
+            // stopped by `reboot`, the vcpu is dropped from the run queue
+            VM_REBOOTING => Some(VCpuExit::Yield),
+            _ => Some(VCpuExit::Halt),
+        }
+    }
+
+    /// Makes the pcpus of all vcpus but `caller` notice the new vm state and waits
+    /// until none of them runs any more.
+    fn stop_other_vcpus(&self, caller: usize) {
+        for vcpu_id in (0..self.vcpus.len()).filter(|id| *id != caller) {
+            sched::enqueue_vcpu((self.vm_id, vcpu_id));
+        }
+        // a pcpu marks a vcpu running before checking the vm state, so once it is
+        // seen not running it will not enter the guest again
+        let caller_ctrl = &self.vcpu_ctrls[caller];
+        let others_running = || {
+            self.vcpu_ctrls
+                .iter()
+                .enumerate()
+                .any(|(vcpu_id, ctrl)| vcpu_id != caller && ctrl.is_running())
+        };
+        while others_running() {
+            // another vcpu may be waiting for a fence on the caller
+            sbi::flush_requested(caller_ctrl);
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Makes sure emulated devices do not overlap guest RAM, as their pages must stay
+/// unmapped.
+fn check_mmio_regions(vm_config: &VMConfig, mmio_bus: &MmioBus) -> HypervisorResult<()> {
+    let ram_start = vm_config.memory_base;
+    let ram_end = ram_start + align_up(vm_config.memory_limit, PAGE_SIZE_4K);
+    for (base, size) in mmio_bus.ranges() {
+        let base = align_down(base.as_usize(), PAGE_SIZE_4K);
+        let end = align_up(base + size, PAGE_SIZE_4K);
+        if base < ram_end && ram_start < end {
+            return Err(HypervisorError::InvalidParam);
+        }
+    }
+    Ok(())
 }
 
-pub fn init_guest_page_table(
+/// Backs guest RAM with frames from the frame allocator and maps them at `memory_base`.
+///
+/// Frames are allocated in chunks of at most `GUEST_MEMORY_CHUNK_SIZE`, so guest RAM
+/// does not need to be contiguous in host physical memory.
+pub fn init_guest_memory(
     vm_config: &VMConfig,
-    meta: &MachineMeta,
-) -> HypervisorResult<GuestPageTable> {
-    let mut guest_page_table = GuestPageTable::try_new()?;
-
-    let guest_memory_base = align_down(vm_config.entry, PAGE_SIZE_4K);
-    let guest_memory_size = align_up(vm_config.memory_limit, PAGE_SIZE_4K);
-    let guest_memory_pages = guest_memory_size / PAGE_SIZE_4K;
-    PHYS_FRAME_ALLOCATOR
-        .lock()
-        .alloc_range(guest_memory_base.into(), guest_memory_pages);
+    guest_page_table: &mut GuestPageTable,
+) -> HypervisorResult<Vec<GuestMemoryRegion>> {
+    assert_eq!(vm_config.memory_base % PAGE_SIZE_4K, 0);
+    let memory_size = align_up(vm_config.memory_limit, PAGE_SIZE_4K);
     let pte_flags = PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::V | PTEFlags::U;
-    guest_page_table.map_region(
-        guest_memory_base.into(),
-        guest_memory_base.into(),
-        guest_memory_pages,
-        pte_flags,
-    )?;
-
-    // copy kernel image to guest memory
-    let kernel_entry: GuestPhysAddr = vm_config.entry.into();
-    let kernel_entry_paddr = guest_page_table.translate(kernel_entry)?;
-    let kernel_image = kernel_image(vm_config.kernel);
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            kernel_image.as_ptr(),
-            kernel_entry_paddr.as_usize() as *mut u8,
-            kernel_image.len(),
+
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < memory_size {
+        let size = (memory_size - offset).min(GUEST_MEMORY_CHUNK_SIZE);
+        let hpa = PHYS_FRAME_ALLOCATOR
+            .lock()
+            .alloc_frames(size / PAGE_SIZE_4K, GUEST_MEMORY_CHUNK_SIZE)?;
+        let gpa = GuestPhysAddr::from(vm_config.memory_base + offset);
+        guest_page_table.map_region(gpa, hpa, size / PAGE_SIZE_4K, pte_flags)?;
+        debug!(
+            "[Hypervisor] map guest memory: [{:?}, {:?}) -> {:?}",
+            gpa,
+            gpa + size,
+            hpa
         );
+        regions.push(GuestMemoryRegion { gpa, hpa, size });
+        offset += size;
     }
 
-    // map mmio
+    for region in regions.iter() {
+        assert_eq!(
+            guest_page_table.query_page(region.gpa).unwrap(),
+            (region.hpa, pte_flags)
+        );
+        assert_eq!(
+            guest_page_table
+                .translate(region.gpa + region.size - 1)
+                .unwrap(),
+            region.hpa + region.size - 1
+        );
+    }
+
+    Ok(regions)
+}
+
+/// Places the device tree in the last pages of guest RAM, which must not overlap
+/// the kernel image.
+fn dtb_base(
+    vm_config: &VMConfig,
+    kernel_size: usize,
+    dtb_size: usize,
+) -> HypervisorResult<GuestPhysAddr> {
+    let ram_end = vm_config.memory_base + align_up(vm_config.memory_limit, PAGE_SIZE_4K);
+    let base = align_down(ram_end - dtb_size, PAGE_SIZE_4K);
+    if base < vm_config.entry + kernel_size || base < vm_config.memory_base {
+        return Err(HypervisorError::NoMemory);
+    }
+    Ok(base.into())
+}
+
+/// Copies an image to `base` page by page through the guest page table.
+pub fn load_image(
+    image: &[u8],
+    base: GuestPhysAddr,
+    guest_page_table: &mut GuestPageTable,
+) -> HypervisorResult<()> {
+    let mut copied = 0;
+    while copied < image.len() {
+        let gpa = base + copied;
+        let hpa = guest_page_table.translate(gpa)?;
+        let len = (PAGE_SIZE_4K - gpa.as_usize() % PAGE_SIZE_4K).min(image.len() - copied);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                image[copied..].as_ptr(),
+                hpa.as_usize() as *mut u8,
+                len,
+            );
+        }
+        copied += len;
+    }
+    Ok(())
+}
+
+/// Identically maps the virtio mmio regions into the guest.
+pub fn map_passthrough_devices(
+    meta: &MachineMeta,
+    guest_page_table: &mut GuestPageTable,
+) -> HypervisorResult<()> {
     for virt_dev in meta.virtio.iter() {
         let pte_flags = PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::U | PTEFlags::X;
         guest_page_table.map_region(
@@ -128,34 +474,5 @@ pub fn init_guest_page_table(
             pte_flags,
         )?;
     }
-
-    let guest_memory_base_paddr: HostPhysAddr = guest_memory_base.into();
-    assert_eq!(
-        guest_page_table
-            .query_page(guest_memory_base.into())
-            .unwrap(),
-        (guest_memory_base_paddr, pte_flags)
-    );
-    if guest_memory_size > PAGE_SIZE_4K {
-        assert_eq!(
-            guest_page_table
-                .query_page((guest_memory_base + PAGE_SIZE_4K).into())
-                .unwrap(),
-            (guest_memory_base_paddr + PAGE_SIZE_4K, pte_flags)
-        );
-    }
-    assert_eq!(
-        guest_page_table
-            .translate((guest_memory_base + 1).into())
-            .unwrap(),
-        guest_memory_base_paddr + 1
-    );
-    assert_eq!(
-        guest_page_table
-            .translate((guest_memory_base + guest_memory_size - 1).into())
-            .unwrap(),
-        guest_memory_base_paddr + guest_memory_size - 1
-    );
-
-    Ok(guest_page_table)
+    Ok(())
 }